@@ -0,0 +1,285 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use chrono::prelude::*;
+use hostname;
+use serde::Serialize;
+use serde_json;
+use backend::ArchiveStatus;
+use error::Error;
+use generation::{IntervalMode, Strategy};
+use group::Group;
+use report::{Action, DeletionResult, Report};
+
+/// An append-only log of the deletion decisions, one JSON object per
+/// line.
+///
+/// Each run appends two lines with the same `run` id. The `decision` line
+/// with the listing and the decisions is flushed to the disk before
+/// anything is deleted, so that it survives a crash during the deletion.
+/// The `result` line with the status of each attempted archive and the
+/// outcome is appended after the deletion.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: File,
+    path: PathBuf,
+    run: String,
+    host: String,
+}
+
+#[derive(Serialize)]
+struct DecisionEntry<'a> {
+    event: &'static str,
+    run: &'a str,
+    timestamp: DateTime<Utc>,
+    host: &'a str,
+    policy: &'a str,
+    now: DateTime<Utc>,
+    dry_run: bool,
+    archives: Vec<ArchiveEntry<'a>>,
+    keep: Vec<&'a str>,
+    delete: Vec<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ArchiveEntry<'a> {
+    name: &'a str,
+    timestamp: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct ResultEntry<'a> {
+    event: &'static str,
+    run: &'a str,
+    timestamp: DateTime<Utc>,
+    host: &'a str,
+    result: &'a Option<DeletionResult>,
+    archives: Vec<StatusEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct StatusEntry<'a> {
+    name: &'a str,
+    status: ArchiveStatus,
+}
+
+impl AuditLog {
+    /// Open the audit log for appending. The file is created if it
+    /// doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AuditLog, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|err| Error::io(path, err))?;
+        let started = Utc::now();
+
+        Ok(AuditLog {
+            file,
+            path: path.to_path_buf(),
+            run: format!("{}-{}", started.format("%Y%m%dT%H%M%S%.3fZ"), process::id()),
+            host: hostname::get_hostname().unwrap_or_default(),
+        })
+    }
+
+    /// Record the listing and the decisions before deleting anything.
+    /// `policy` describes the generations, the strategy and the interval
+    /// mode, see `describe_policy`.
+    pub fn record_decision(&mut self, policy: &str, report: &Report) -> Result<(), Error> {
+        let entry = DecisionEntry {
+            event: "decision",
+            run: &self.run,
+            timestamp: Utc::now(),
+            host: &self.host,
+            policy,
+            now: report.now,
+            dry_run: report.dry_run,
+            archives: report
+                .snapshots
+                .iter()
+                .map(|x| ArchiveEntry {
+                    name: &x.name,
+                    timestamp: x.timestamp,
+                })
+                .collect(),
+            keep: names(report, Action::Keep),
+            delete: names(report, Action::Delete),
+        };
+        self.append(&entry)
+    }
+
+    /// Record the outcome of the deletion. The archive statuses and the
+    /// result are taken from `report`.
+    pub fn record_result(&mut self, report: &Report) -> Result<(), Error> {
+        let entry = ResultEntry {
+            event: "result",
+            run: &self.run,
+            timestamp: Utc::now(),
+            host: &self.host,
+            result: &report.deletion,
+            archives: report
+                .snapshots
+                .iter()
+                .filter_map(|x| {
+                    x.status.map(|status| StatusEntry {
+                        name: &x.name,
+                        status,
+                    })
+                })
+                .collect(),
+        };
+        self.append(&entry)
+    }
+
+    // Write one line and wait until it is on the disk
+    fn append<T: Serialize>(&self, entry: &T) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry).unwrap();
+        line.push('\n');
+        let mut file = &self.file;
+        file.write_all(line.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|err| Error::io(&self.path, err))
+    }
+}
+
+// The names of the snapshots with the given action
+fn names(report: &Report, action: Action) -> Vec<&str> {
+    report
+        .snapshots
+        .iter()
+        .filter(|x| x.action == action)
+        .map(|x| x.name.as_str())
+        .collect()
+}
+
+/// Describe the generations of each group, the strategy and the interval
+/// mode, for example
+/// "db: 48H 31D; all: 31D 12M; strategy: nearest; intervals: calendar".
+pub fn describe_policy(groups: &[Group], strategy: Strategy, mode: IntervalMode) -> String {
+    let mut parts: Vec<String> = groups
+        .iter()
+        .map(|group| {
+            let generations: Vec<String> =
+                group.generations.iter().map(|x| x.to_string()).collect();
+            format!("{}: {}", group.name, generations.join(" "))
+        })
+        .collect();
+    parts.push(format!("strategy: {}", strategy));
+    parts.push(format!("intervals: {}", mode));
+
+    parts.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+    use chrono_tz::Tz;
+    use backend::ArchiveDeletion;
+    use generation::{IntervalMode, KeepReason, parse_generations};
+    use group::Decision;
+    use snapshot::Snapshot;
+
+    #[test]
+    fn policy_description() {
+        let generations = |args: &[&str]| {
+            parse_generations(
+                args.iter().map(|x| x.to_string()).collect(),
+                IntervalMode::Calendar,
+                Tz::UTC,
+            ).unwrap()
+        };
+        let db = Group {
+            name: "db".to_string(),
+            pattern: ::regex::Regex::new("^db-").unwrap(),
+            generations: generations(&["48H", "31D"]),
+        };
+        let groups = vec![db, Group::all(generations(&["31D", "12M"]))];
+
+        assert_eq!(
+            describe_policy(&groups, Strategy::Nearest, IntervalMode::Calendar),
+            "db: 48H 31D; all: 31D 12M; strategy: nearest; intervals: calendar"
+        );
+        assert_eq!(
+            describe_policy(&groups, Strategy::BucketOldest, IntervalMode::Fixed),
+            "db: 48H 31D; all: 31D 12M; strategy: bucket-oldest; intervals: fixed"
+        );
+    }
+
+    #[test]
+    fn append_entries() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.log");
+        fs::write(&path, "{\"event\":\"earlier\"}\n").unwrap();
+        let now = Utc.ymd(2018, 7, 20).and_hms(0, 0, 0);
+        let decision = |name: &str, reasons| Decision {
+            snapshot: Snapshot {
+                name: name.to_string(),
+                ts: Utc.ymd(2018, 7, 19).and_hms(12, 0, 0),
+            },
+            group: Some("all".to_string()),
+            reasons,
+        };
+        let mut report = Report::new(
+            &now,
+            false,
+            &[decision("a", vec![]), decision("b", vec![KeepReason::Latest])],
+        );
+
+        let mut log = AuditLog::open(&path).unwrap();
+        log.record_decision("all: 1D", &report).unwrap();
+        report.set_archive_statuses(&[ArchiveDeletion {
+            name: "a".to_string(),
+            status: ArchiveStatus::Deleted,
+        }]);
+        report.set_deletion_result(&Ok(()));
+        log.record_result(&report).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["event"], "decision");
+        assert_eq!(lines[1]["policy"], "all: 1D");
+        assert_eq!(lines[1]["now"], "2018-07-20T00:00:00Z");
+        assert_eq!(lines[1]["archives"][0]["name"], "a");
+        assert_eq!(lines[1]["archives"][0]["timestamp"], "2018-07-19T12:00:00Z");
+        assert_eq!(lines[1]["keep"], serde_json::json!(["b"]));
+        assert_eq!(lines[1]["delete"], serde_json::json!(["a"]));
+        assert_eq!(lines[2]["event"], "result");
+        assert_eq!(lines[2]["run"], lines[1]["run"]);
+        assert_eq!(lines[2]["archives"], serde_json::json!([{"name": "a", "status": "deleted"}]));
+        assert_eq!(lines[2]["result"]["status"], "deleted");
+    }
+}
@@ -0,0 +1,476 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::collections::{HashMap, HashSet};
+use error::Error;
+use snapshot::Snapshot;
+use stats::{StorageSizes, StorageStats};
+
+/// Storage that holds the archives to be rotated.
+pub trait ArchiveBackend {
+    /// Return all archives in the storage.
+    fn list_archives(&self) -> Result<Vec<Snapshot>, Error>;
+
+    /// Delete the named archives.
+    fn delete_archives(&mut self, names: &[String]) -> Result<(), Error>;
+
+    /// Return the storage sizes of all archives together and of each of
+    /// the named archives. By default the sizes are not supported.
+    fn print_stats(&self, _names: &[String]) -> Result<StorageStats, Error> {
+        Err(Error::invalid_argument(
+            "--print-stats",
+            "the archive backend doesn't report storage sizes",
+        ))
+    }
+}
+
+/// An archive backend that keeps the archives in memory. Useful for tests.
+///
+/// The storage sizes of the archives are given in `sizes`. An archive
+/// without sizes is empty, and no data is shared between archives.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBackend {
+    pub archives: Vec<Snapshot>,
+    pub sizes: HashMap<String, StorageSizes>,
+}
+
+impl MemoryBackend {
+    pub fn new(archives: Vec<Snapshot>) -> MemoryBackend {
+        MemoryBackend {
+            archives,
+            sizes: HashMap::new(),
+        }
+    }
+
+    fn sizes(&self, name: &str) -> StorageSizes {
+        self.sizes.get(name).cloned().unwrap_or_default()
+    }
+}
+
+impl ArchiveBackend for MemoryBackend {
+    fn list_archives(&self) -> Result<Vec<Snapshot>, Error> {
+        Ok(self.archives.clone())
+    }
+
+    fn delete_archives(&mut self, names: &[String]) -> Result<(), Error> {
+        match names.iter().find(|name| {
+            !self.archives.iter().any(|x| &x.name == *name)
+        }) {
+            Some(missing) => Err(Error::ArchiveNotFound(missing.clone())),
+            None => {
+                self.archives.retain(|x| !names.contains(&x.name));
+                Ok(())
+            }
+        }
+    }
+
+    fn print_stats(&self, names: &[String]) -> Result<StorageStats, Error> {
+        let mut archives = Vec::new();
+        for name in names {
+            if !self.archives.iter().any(|x| &x.name == name) {
+                return Err(Error::ArchiveNotFound(name.clone()));
+            }
+            archives.push((name.clone(), self.sizes(name)));
+        }
+        let all = self
+            .archives
+            .iter()
+            .fold(StorageSizes::default(), |all, x| all + self.sizes(&x.name));
+
+        Ok(StorageStats { all, archives })
+    }
+}
+
+/// Delete the named snapshots from the backend in one call to
+/// `ArchiveBackend::delete_archives`. Nothing is deleted if `dry_run` is
+/// true, and the backend isn't accessed at all.
+///
+/// The archives are not listed before or after the deletion. Use
+/// `delete_in_batches` to know the status of each archive.
+pub fn delete_snapshots<B: ArchiveBackend>(
+    backend: &mut B,
+    snapshot_names: Vec<String>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let sorted_names = sort_selected(snapshot_names);
+    if dry_run || sorted_names.is_empty() {
+        Ok(())
+    } else {
+        backend.delete_archives(&sorted_names)
+    }
+}
+
+/// What happened to an archive that was selected for deletion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ArchiveStatus {
+    /// The archive is gone.
+    Deleted,
+    /// The archive still exists after the deletion was attempted, or it
+    /// wasn't in the listing taken before the deletion.
+    NotDeleted,
+    /// The deletion failed and the archives couldn't be listed afterwards.
+    Unknown,
+    /// The deletion wasn't attempted because an earlier batch failed.
+    Skipped,
+    /// The archive would have been deleted without --dry-run.
+    DryRun,
+}
+
+/// The status of one archive after `delete_in_batches`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ArchiveDeletion {
+    pub name: String,
+    pub status: ArchiveStatus,
+}
+
+/// The outcome of `delete_in_batches`: the status of each archive and the
+/// first error, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deletion {
+    pub archives: Vec<ArchiveDeletion>,
+    pub result: Result<(), Error>,
+}
+
+/// Delete the named snapshots from the backend, at most `batch_size`
+/// archives at a time. All archives are deleted at once if `batch_size`
+/// is `None`. The batches after a failed one are skipped.
+///
+/// `existing` is the listing of the archives before the deletion. Names
+/// that are not in it are not passed to the backend, and the deletion
+/// fails with `Error::ArchiveNotFound`. The archives are listed again
+/// afterwards to confirm which ones are gone, so that the status of each
+/// archive is known even if a batch fails halfway. A later run lists the
+/// archives anew and continues from where a failed run stopped. Nothing
+/// is deleted if `dry_run` is true.
+pub fn delete_in_batches<B: ArchiveBackend>(
+    backend: &mut B,
+    existing: &[Snapshot],
+    snapshot_names: Vec<String>,
+    dry_run: bool,
+    batch_size: Option<usize>,
+) -> Deletion {
+    let sorted_names = sort_selected(snapshot_names);
+    if sorted_names.is_empty() {
+        return Deletion {
+            archives: Vec::new(),
+            result: Ok(()),
+        };
+    }
+    if dry_run {
+        return Deletion {
+            archives: with_status(&sorted_names, |_| ArchiveStatus::DryRun),
+            result: Ok(()),
+        };
+    }
+
+    let existing: HashSet<&str> = existing.iter().map(|x| x.name.as_str()).collect();
+    let (present, missing): (Vec<String>, Vec<String>) = sorted_names
+        .iter()
+        .cloned()
+        .partition(|name| existing.contains(name.as_str()));
+    let mut result = match missing.first() {
+        Some(name) => Err(Error::ArchiveNotFound(name.clone())),
+        None => Ok(()),
+    };
+
+    let batch_size = batch_size.unwrap_or(present.len()).max(1);
+    let mut confirmed = HashSet::new();
+    let mut attempted = HashSet::new();
+    for batch in present.chunks(batch_size) {
+        debug!("Deleting {} archives", batch.len());
+        attempted.extend(batch.iter().map(|x| x.as_str()));
+        if let Err(err) = backend.delete_archives(batch) {
+            result = result.and(Err(err));
+            break;
+        }
+        confirmed.extend(batch.iter().map(|x| x.as_str()));
+    }
+
+    let archives = match backend.list_archives() {
+        Ok(remaining) => {
+            let remaining: HashSet<&str> = remaining.iter().map(|x| x.name.as_str()).collect();
+            with_status(&sorted_names, |name| if !existing.contains(name) {
+                ArchiveStatus::NotDeleted
+            } else if !attempted.contains(name) {
+                ArchiveStatus::Skipped
+            } else if remaining.contains(name) {
+                ArchiveStatus::NotDeleted
+            } else {
+                ArchiveStatus::Deleted
+            })
+        }
+        Err(err) => {
+            warn!("Failed to list the archives after deleting: {}", err);
+            with_status(&sorted_names, |name| if confirmed.contains(name) {
+                ArchiveStatus::Deleted
+            } else if attempted.contains(name) {
+                ArchiveStatus::Unknown
+            } else if !existing.contains(name) {
+                ArchiveStatus::NotDeleted
+            } else {
+                ArchiveStatus::Skipped
+            })
+        }
+    };
+
+    let not_deleted: Vec<String> = archives
+        .iter()
+        .filter(|x| x.status == ArchiveStatus::NotDeleted)
+        .map(|x| x.name.clone())
+        .collect();
+    if result.is_ok() && !not_deleted.is_empty() {
+        result = Err(Error::NotDeleted(not_deleted));
+    }
+
+    Deletion { archives, result }
+}
+
+// Sort the names of the snapshots selected for deletion and log them
+fn sort_selected(snapshot_names: Vec<String>) -> Vec<String> {
+    let mut sorted_names = snapshot_names;
+    sorted_names.sort_unstable();
+    if sorted_names.is_empty() {
+        info!("Didn't find anything to expire");
+    } else {
+        info!(
+            "snapshots selected for deletion: {}",
+            sorted_names.join(", ")
+        );
+    }
+
+    sorted_names
+}
+
+// The status of each archive, given its name
+fn with_status<F>(names: &[String], status: F) -> Vec<ArchiveDeletion>
+where
+    F: Fn(&str) -> ArchiveStatus,
+{
+    names
+        .iter()
+        .map(|name| ArchiveDeletion {
+            name: name.clone(),
+            status: status(name),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::prelude::*;
+
+    fn test_backend() -> MemoryBackend {
+        MemoryBackend::new(vec![
+            Snapshot {
+                name: "archive-001".to_string(),
+                ts: Utc.ymd(2018, 7, 22).and_hms(15, 10, 48),
+            },
+            Snapshot {
+                name: "archive-002".to_string(),
+                ts: Utc.ymd(2018, 7, 23).and_hms(23, 43, 51),
+            },
+        ])
+    }
+
+    fn names(backend: &MemoryBackend) -> Vec<String> {
+        backend.archives.iter().map(|x| x.name.clone()).collect()
+    }
+
+    #[test]
+    fn delete_from_memory() {
+        let mut backend = test_backend();
+        let res = delete_snapshots(&mut backend, vec!["archive-001".to_string()], false);
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(names(&backend), vec!["archive-002".to_string()]);
+    }
+
+    #[test]
+    fn delete_dry_run() {
+        let mut backend = test_backend();
+        let res = delete_snapshots(&mut backend, vec!["archive-001".to_string()], true);
+
+        assert_eq!(res, Ok(()));
+        assert_eq!(backend.archives, test_backend().archives);
+    }
+
+    // A backend that can't be listed or deleted from
+    struct UnreachableBackend;
+
+    impl ArchiveBackend for UnreachableBackend {
+        fn list_archives(&self) -> Result<Vec<Snapshot>, Error> {
+            panic!("listed the archives")
+        }
+
+        fn delete_archives(&mut self, _names: &[String]) -> Result<(), Error> {
+            panic!("deleted archives")
+        }
+    }
+
+    #[test]
+    fn dry_run_doesnt_access_backend() {
+        let res = delete_snapshots(&mut UnreachableBackend, vec!["archive-001".to_string()], true);
+
+        assert_eq!(res, Ok(()));
+    }
+
+    #[test]
+    fn delete_missing_archive() {
+        let mut backend = test_backend();
+        let res = delete_snapshots(&mut backend, vec!["archive-003".to_string()], false);
+
+        assert_eq!(res, Err(Error::ArchiveNotFound("archive-003".to_string())));
+        assert_eq!(backend.archives, test_backend().archives);
+    }
+
+    #[test]
+    fn memory_stats() {
+        let mut backend = test_backend();
+        let sizes = StorageSizes {
+            total_size: 1000,
+            compressed_size: 400,
+            unique_size: 1000,
+            unique_compressed_size: 400,
+        };
+        backend.sizes.insert("archive-001".to_string(), sizes);
+
+        let stats = backend.print_stats(&["archive-002".to_string()]).unwrap();
+        assert_eq!(stats.all, sizes);
+        assert_eq!(stats.archives, vec![("archive-002".to_string(), StorageSizes::default())]);
+        assert_eq!(
+            backend.print_stats(&["archive-003".to_string()]),
+            Err(Error::ArchiveNotFound("archive-003".to_string()))
+        );
+    }
+
+    fn statuses(deletion: &Deletion) -> Vec<(&str, ArchiveStatus)> {
+        deletion
+            .archives
+            .iter()
+            .map(|x| (x.name.as_str(), x.status))
+            .collect()
+    }
+
+    // A backend whose deletions fail if they include the archive `fail`
+    struct FailingBackend {
+        inner: MemoryBackend,
+        fail: String,
+    }
+
+    impl ArchiveBackend for FailingBackend {
+        fn list_archives(&self) -> Result<Vec<Snapshot>, Error> {
+            self.inner.list_archives()
+        }
+
+        fn delete_archives(&mut self, names: &[String]) -> Result<(), Error> {
+            if names.contains(&self.fail) {
+                return Err(Error::Tarsnap {
+                    exit_code: Some(1),
+                    stderr: "tarsnap: Connection lost".to_string(),
+                });
+            }
+            self.inner.delete_archives(names)
+        }
+    }
+
+    #[test]
+    fn delete_batches() {
+        let mut backend = test_backend();
+        let existing = backend.archives.clone();
+        let names = vec!["archive-002".to_string(), "archive-001".to_string()];
+        let deletion = delete_in_batches(&mut backend, &existing, names, false, Some(1));
+
+        assert_eq!(deletion.result, Ok(()));
+        assert_eq!(
+            statuses(&deletion),
+            vec![
+                ("archive-001", ArchiveStatus::Deleted),
+                ("archive-002", ArchiveStatus::Deleted),
+            ]
+        );
+        assert!(backend.archives.is_empty());
+    }
+
+    #[test]
+    fn failed_batch_skips_the_rest() {
+        let mut backend = FailingBackend {
+            inner: test_backend(),
+            fail: "archive-001".to_string(),
+        };
+        let existing = backend.inner.archives.clone();
+        let names = vec!["archive-002".to_string(), "archive-001".to_string()];
+        let deletion = delete_in_batches(&mut backend, &existing, names, false, Some(1));
+
+        match deletion.result {
+            Err(Error::Tarsnap { exit_code: Some(1), .. }) => (),
+            ref other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            statuses(&deletion),
+            vec![
+                ("archive-001", ArchiveStatus::NotDeleted),
+                ("archive-002", ArchiveStatus::Skipped),
+            ]
+        );
+        assert_eq!(backend.inner.archives, test_backend().archives);
+    }
+
+    #[test]
+    fn missing_archive_is_not_deleted() {
+        let mut backend = test_backend();
+        let existing = backend.archives.clone();
+        let names = vec![
+            "archive-001".to_string(),
+            "archive-000".to_string(),
+            "archive-002".to_string(),
+        ];
+        let deletion = delete_in_batches(&mut backend, &existing, names, false, Some(2));
+
+        assert_eq!(deletion.result, Err(Error::ArchiveNotFound("archive-000".to_string())));
+        assert_eq!(
+            statuses(&deletion),
+            vec![
+                ("archive-000", ArchiveStatus::NotDeleted),
+                ("archive-001", ArchiveStatus::Deleted),
+                ("archive-002", ArchiveStatus::Deleted),
+            ]
+        );
+        assert!(backend.archives.is_empty());
+    }
+
+    #[test]
+    fn delete_batches_dry_run() {
+        let mut backend = test_backend();
+        let names = vec!["archive-001".to_string()];
+        let deletion = delete_in_batches(&mut backend, &[], names, true, None);
+
+        assert_eq!(deletion.result, Ok(()));
+        assert_eq!(statuses(&deletion), vec![("archive-001", ArchiveStatus::DryRun)]);
+        assert_eq!(backend.archives, test_backend().archives);
+    }
+}
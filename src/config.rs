@@ -0,0 +1,725 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use regex::Regex;
+use toml;
+use toml::Spanned;
+use chrono::Duration;
+use chrono_tz::Tz;
+use error::Error;
+use generation::{IntervalMode, Strategy, parse_generations};
+use duration::parse_duration;
+use group::glob_to_regex;
+use limit::parse_percent;
+use timestamp::{NameTimestamp, TimestampFallback, parse_timezone};
+
+/// Settings of one named policy in the configuration file. Missing
+/// settings are `None` and fall back to the command line defaults.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PolicyConfig {
+    pub generations: Option<Vec<String>>,
+    pub strategy: Option<Strategy>,
+    pub fixed_intervals: Option<bool>,
+    pub timezone: Option<Tz>,
+    pub dry_run: Option<bool>,
+    pub print_stats: Option<bool>,
+    pub groups: Vec<GroupConfig>,
+    pub rotate_ungrouped: Option<bool>,
+    pub timestamp_from_name: Option<String>,
+    pub timestamp_fallback: Option<TimestampFallback>,
+    pub keyfile: Option<PathBuf>,
+    pub delete_keyfile: Option<PathBuf>,
+    pub cachedir: Option<PathBuf>,
+    pub configfile: Option<PathBuf>,
+    pub tarsnap_args: Option<Vec<String>>,
+    pub tarsnap_path: Option<PathBuf>,
+    pub tarsnap_prefix: Option<Vec<String>>,
+    pub null_output: Option<bool>,
+    pub strict_names: Option<bool>,
+    pub batch_size: Option<usize>,
+    pub lock_file: Option<PathBuf>,
+    pub lock_wait: Option<Duration>,
+    pub audit_log: Option<PathBuf>,
+    pub metrics_file: Option<PathBuf>,
+    pub max_delete: Option<usize>,
+    pub max_delete_percent: Option<f64>,
+    pub keep_last: Option<usize>,
+    pub min_age: Option<Duration>,
+    pub max_future: Option<Duration>,
+    pub max_newest_age: Option<Duration>,
+    pub protect: Option<Vec<String>>,
+    pub protect_file: Option<PathBuf>,
+    pub holds: Option<PathBuf>,
+}
+
+/// Settings of one archive group. `pattern` is a regular expression.
+/// The group uses the generations of the policy if `generations` is
+/// `None`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupConfig {
+    pub name: String,
+    pub pattern: String,
+    pub generations: Option<Vec<String>>,
+}
+
+impl FromStr for GroupConfig {
+    type Err = Error;
+
+    /// Parse a group from a command line argument of the form
+    /// `GLOB=GENERATIONS`, for example `db-*=48H,31D`. The generations
+    /// are optional.
+    fn from_str(s: &str) -> Result<GroupConfig, Error> {
+        let (glob, generations) = match s.rfind('=') {
+            Some(i) => {
+                let args: Vec<String> = s[i + 1..].split(',').map(|x| x.to_string()).collect();
+                parse_generations(args.clone(), IntervalMode::Calendar, Tz::UTC)?;
+                (&s[..i], Some(args))
+            }
+            None => (s, None),
+        };
+
+        Ok(GroupConfig {
+            name: glob.to_string(),
+            pattern: glob_to_regex(glob),
+            generations,
+        })
+    }
+}
+
+/// A parsed and validated configuration file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub policies: BTreeMap<String, PolicyConfig>,
+}
+
+impl Config {
+    /// Return the named policy.
+    pub fn policy(&self, name: &str) -> Result<&PolicyConfig, Error> {
+        self.policies.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.policies.keys().map(|x| x.as_str()).collect();
+            Error::Config {
+                path: None,
+                line: None,
+                message: format!(
+                    "Policy {} not found in the configuration file. Available policies: {}",
+                    name,
+                    names.join(", ")
+                ),
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    policy: BTreeMap<String, RawPolicy>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RawPolicy {
+    generations: Option<Vec<Spanned<String>>>,
+    strategy: Option<Spanned<String>>,
+    fixed_intervals: Option<bool>,
+    timezone: Option<Spanned<String>>,
+    dry_run: Option<bool>,
+    print_stats: Option<bool>,
+    #[serde(default)]
+    group: Vec<RawGroup>,
+    rotate_ungrouped: Option<bool>,
+    timestamp_from_name: Option<Spanned<String>>,
+    timestamp_fallback: Option<Spanned<String>>,
+    keyfile: Option<PathBuf>,
+    delete_keyfile: Option<PathBuf>,
+    cachedir: Option<PathBuf>,
+    configfile: Option<PathBuf>,
+    tarsnap_args: Option<Vec<String>>,
+    tarsnap_path: Option<PathBuf>,
+    tarsnap_prefix: Option<Vec<String>>,
+    null_output: Option<bool>,
+    strict_names: Option<bool>,
+    batch_size: Option<usize>,
+    lock_file: Option<PathBuf>,
+    lock_wait: Option<Spanned<String>>,
+    audit_log: Option<PathBuf>,
+    metrics_file: Option<PathBuf>,
+    max_delete: Option<usize>,
+    max_delete_percent: Option<Spanned<f64>>,
+    keep_last: Option<usize>,
+    min_age: Option<Spanned<String>>,
+    max_future: Option<Spanned<String>>,
+    max_newest_age: Option<Spanned<String>>,
+    protect: Option<Vec<Spanned<String>>>,
+    protect_file: Option<PathBuf>,
+    holds: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct RawGroup {
+    name: Option<String>,
+    glob: Option<String>,
+    regex: Option<Spanned<String>>,
+    generations: Option<Vec<Spanned<String>>>,
+}
+
+/// Read and validate a TOML configuration file.
+///
+/// An example configuration:
+///
+/// ```toml
+/// [policy.default]
+/// generations = ["31D", "10W", "12M"]
+///
+/// [policy.hourly]
+/// generations = ["48H", "31D"]
+/// strategy = "bucket-oldest"
+///
+/// keyfile = "/root/tarsnap-read.key"
+/// delete-keyfile = "/root/tarsnap-delete.key"
+/// cachedir = "/usr/local/tarsnap-cache"
+///
+/// [[policy.hourly.group]]
+/// glob = "db-*"
+/// generations = ["48H", "31D", "12M"]
+///
+/// [[policy.hourly.group]]
+/// name = "etc"
+/// regex = "^etc-"
+/// ```
+pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, Error> {
+    let path = path.as_ref();
+    fs::read_to_string(path)
+        .map_err(|err| Error::Config {
+            path: Some(path.to_path_buf()),
+            line: None,
+            message: err.to_string(),
+        })
+        .and_then(|source| {
+            parse_config(&source).map_err(|err| match err {
+                Error::Config { line, message, .. } => Error::Config {
+                    path: Some(path.to_path_buf()),
+                    line,
+                    message,
+                },
+                err => err,
+            })
+        })
+}
+
+/// Parse and validate the contents of a configuration file. The errors
+/// include the line number of the offending value.
+pub fn parse_config(source: &str) -> Result<Config, Error> {
+    let raw: RawConfig = toml::from_str(source).map_err(|err| {
+        let mut message = err.to_string();
+        let line = match unknown_key_line(source, &err) {
+            Some(line) => {
+                // The position in the message is that of the table
+                if let Some(i) = message.rfind(" at line ") {
+                    message.truncate(i);
+                }
+                Some(line)
+            }
+            None => err.line_col().map(|(line, _)| line + 1),
+        };
+        Error::Config {
+            path: None,
+            line,
+            message,
+        }
+    })?;
+
+    let mut policies = BTreeMap::new();
+    for (name, raw_policy) in raw.policy {
+        let policy = validate_policy(source, &name, raw_policy)
+            .map_err(|(line, msg)| Error::Config {
+                path: None,
+                line: Some(line),
+                message: format!("policy {}: {}", name, msg),
+            })?;
+        policies.insert(name, policy);
+    }
+
+    Ok(Config { policies })
+}
+
+fn validate_policy(
+    source: &str,
+    name: &str,
+    raw: RawPolicy,
+) -> Result<PolicyConfig, (usize, String)> {
+    let generations = validate_generations(source, raw.generations)?;
+
+    let strategy = match raw.strategy {
+        Some(s) => Some(s.get_ref().parse().map_err(|err| spanned_error(source, &s, err))?),
+        None => None,
+    };
+
+    let timezone = match raw.timezone {
+        Some(tz) => {
+            Some(parse_timezone(tz.get_ref()).map_err(|err| spanned_error(source, &tz, err))?)
+        }
+        None => None,
+    };
+
+    let max_delete_percent = match raw.max_delete_percent {
+        Some(p) => Some(
+            parse_percent(&p.get_ref().to_string()).map_err(|err| spanned_error(source, &p, err))?,
+        ),
+        None => None,
+    };
+
+    let min_age = validate_duration(source, raw.min_age)?;
+    let lock_wait = validate_duration(source, raw.lock_wait)?;
+    let max_future = validate_duration(source, raw.max_future)?;
+    let max_newest_age = validate_duration(source, raw.max_newest_age)?;
+
+    let protect = match raw.protect {
+        Some(patterns) => {
+            let mut res = Vec::new();
+            for pattern in patterns {
+                Regex::new(pattern.get_ref()).map_err(|err| spanned_error(source, &pattern, err))?;
+                res.push(pattern.into_inner());
+            }
+            Some(res)
+        }
+        None => None,
+    };
+
+    if let Some(ref format) = raw.timestamp_from_name {
+        format
+            .get_ref()
+            .parse::<NameTimestamp>()
+            .map_err(|err| spanned_error(source, format, err))?;
+    }
+
+    let timestamp_fallback = match raw.timestamp_fallback {
+        Some(s) => Some(s.get_ref().parse().map_err(|err| spanned_error(source, &s, err))?),
+        None => None,
+    };
+
+    let mut groups = Vec::new();
+    let group_header = format!("[[policy.{}.group]]", name);
+    for (i, raw_group) in raw.group.into_iter().enumerate() {
+        let line = header_line(source, &group_header, i);
+        groups.push(validate_group(source, line, raw_group)?);
+    }
+
+    Ok(PolicyConfig {
+        generations,
+        strategy,
+        fixed_intervals: raw.fixed_intervals,
+        timezone,
+        dry_run: raw.dry_run,
+        print_stats: raw.print_stats,
+        groups,
+        rotate_ungrouped: raw.rotate_ungrouped,
+        timestamp_from_name: raw.timestamp_from_name.map(|x| x.into_inner()),
+        timestamp_fallback,
+        keyfile: raw.keyfile,
+        delete_keyfile: raw.delete_keyfile,
+        cachedir: raw.cachedir,
+        configfile: raw.configfile,
+        tarsnap_args: raw.tarsnap_args,
+        tarsnap_path: raw.tarsnap_path,
+        tarsnap_prefix: raw.tarsnap_prefix,
+        null_output: raw.null_output,
+        strict_names: raw.strict_names,
+        batch_size: raw.batch_size,
+        lock_file: raw.lock_file,
+        lock_wait,
+        audit_log: raw.audit_log,
+        metrics_file: raw.metrics_file,
+        max_delete: raw.max_delete,
+        max_delete_percent,
+        keep_last: raw.keep_last,
+        min_age,
+        max_future,
+        max_newest_age,
+        protect,
+        protect_file: raw.protect_file,
+        holds: raw.holds,
+    })
+}
+
+fn validate_group(
+    source: &str,
+    line: usize,
+    raw: RawGroup,
+) -> Result<GroupConfig, (usize, String)> {
+    // Like on the command line, an unnamed group is named after its glob
+    let (label, pattern) = match (raw.glob, raw.regex) {
+        (Some(glob), None) => (glob.clone(), glob_to_regex(&glob)),
+        (None, Some(re)) => {
+            Regex::new(re.get_ref()).map_err(|err| spanned_error(source, &re, err))?;
+            (re.get_ref().clone(), re.into_inner())
+        }
+        _ => return Err((line, "A group needs either glob or regex".to_string())),
+    };
+
+    Ok(GroupConfig {
+        name: raw.name.unwrap_or(label),
+        pattern,
+        generations: validate_generations(source, raw.generations)?,
+    })
+}
+
+fn validate_generations(
+    source: &str,
+    generations: Option<Vec<Spanned<String>>>,
+) -> Result<Option<Vec<String>>, (usize, String)> {
+    match generations {
+        Some(args) => {
+            for arg in &args {
+                parse_generations(vec![arg.get_ref().clone()], IntervalMode::Calendar, Tz::UTC)
+                    .map_err(|err| spanned_error(source, arg, err))?;
+            }
+            Ok(Some(args.into_iter().map(|x| x.into_inner()).collect()))
+        }
+        None => Ok(None),
+    }
+}
+
+fn validate_duration(
+    source: &str,
+    duration: Option<Spanned<String>>,
+) -> Result<Option<Duration>, (usize, String)> {
+    match duration {
+        Some(s) => Ok(Some(
+            parse_duration(s.get_ref()).map_err(|err| spanned_error(source, &s, err))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+// The line number of the nth occurrence of a table header. Falls back to
+// the first line if the header is written in some other way.
+fn header_line(source: &str, header: &str, n: usize) -> usize {
+    source
+        .lines()
+        .enumerate()
+        .filter(|&(_, line)| line.trim() == header)
+        .nth(n)
+        .map_or(1, |(i, _)| i + 1)
+}
+
+// serde reports an unknown key at the start of the table that contains
+// it. Find the line of the key itself, searching from the table onwards.
+fn unknown_key_line(source: &str, err: &toml::de::Error) -> Option<usize> {
+    let message = err.to_string();
+    let key = message.split("unknown field `").nth(1)?.split('`').next()?;
+    let (table_line, _) = err.line_col()?;
+    source
+        .lines()
+        .enumerate()
+        .skip(table_line)
+        .find(|&(_, line)| {
+            let line = line.trim_start();
+            [key.to_string(), format!("\"{}\"", key)].iter().any(|k| {
+                line.starts_with(k.as_str()) && line[k.len()..].trim_start().starts_with('=')
+            })
+        })
+        .map(|(i, _)| i + 1)
+}
+
+fn spanned_error<T, E: ToString>(source: &str, value: &Spanned<T>, err: E) -> (usize, String) {
+    (line_of(source, value), err.to_string())
+}
+
+// The line number (starting from 1) of a value in the source
+fn line_of<T>(source: &str, value: &Spanned<T>) -> usize {
+    source[..value.start()].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_config() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D", "10W", "12M"]
+
+            [policy.hourly]
+            generations = ["48H"]
+            strategy = "bucket-oldest"
+            fixed-intervals = true
+            timezone = "Europe/Helsinki"
+            dry-run = true
+            print-stats = true
+            timestamp-from-name = "%Y-%m-%d_%H-%M-%S"
+            timestamp-fallback = "skip"
+            keyfile = "/root/read.key"
+            delete-keyfile = "/root/delete.key"
+            cachedir = "/var/cache/tarsnap"
+            configfile = "/etc/tarsnap.conf"
+            tarsnap-args = ["--humanize-numbers"]
+            tarsnap-path = "/usr/local/bin/tarsnap"
+            tarsnap-prefix = ["nice", "-n", "10"]
+            null-output = false
+            strict-names = true
+            batch-size = 50
+            lock-file = "/var/run/tarsnap-lifespan.lock"
+            lock-wait = "10m"
+            audit-log = "/var/log/tarsnap-lifespan.log"
+            metrics-file = "/var/lib/node_exporter/tarsnap.prom"
+            max-delete = 10
+            max-delete-percent = 25.0
+            keep-last = 3
+            min-age = "48h"
+            max-future = "1h"
+            max-newest-age = "3d"
+            protect = ["^release-"]
+            protect-file = "/etc/tarsnap-lifespan/protected"
+            holds = "/var/lib/tarsnap-lifespan/holds.toml"
+            "#
+        );
+
+        let config = parse_config(source).unwrap();
+        assert_eq!(
+            config.policy("default"),
+            Ok(&PolicyConfig {
+                generations: Some(vec!["31D".to_string(), "10W".to_string(), "12M".to_string()]),
+                ..PolicyConfig::default()
+            })
+        );
+        assert_eq!(
+            config.policy("hourly"),
+            Ok(&PolicyConfig {
+                generations: Some(vec!["48H".to_string()]),
+                strategy: Some(Strategy::BucketOldest),
+                fixed_intervals: Some(true),
+                timezone: Some(Tz::Europe__Helsinki),
+                dry_run: Some(true),
+                print_stats: Some(true),
+                timestamp_from_name: Some("%Y-%m-%d_%H-%M-%S".to_string()),
+                timestamp_fallback: Some(TimestampFallback::Skip),
+                keyfile: Some(PathBuf::from("/root/read.key")),
+                delete_keyfile: Some(PathBuf::from("/root/delete.key")),
+                cachedir: Some(PathBuf::from("/var/cache/tarsnap")),
+                configfile: Some(PathBuf::from("/etc/tarsnap.conf")),
+                tarsnap_args: Some(vec!["--humanize-numbers".to_string()]),
+                tarsnap_path: Some(PathBuf::from("/usr/local/bin/tarsnap")),
+                tarsnap_prefix: Some(vec!["nice".to_string(), "-n".to_string(), "10".to_string()]),
+                null_output: Some(false),
+                strict_names: Some(true),
+                batch_size: Some(50),
+                lock_file: Some(PathBuf::from("/var/run/tarsnap-lifespan.lock")),
+                lock_wait: Some(Duration::minutes(10)),
+                audit_log: Some(PathBuf::from("/var/log/tarsnap-lifespan.log")),
+                metrics_file: Some(PathBuf::from("/var/lib/node_exporter/tarsnap.prom")),
+                max_delete: Some(10),
+                max_delete_percent: Some(25.0),
+                keep_last: Some(3),
+                min_age: Some(Duration::hours(48)),
+                max_future: Some(Duration::hours(1)),
+                max_newest_age: Some(Duration::days(3)),
+                protect: Some(vec!["^release-".to_string()]),
+                protect_file: Some(PathBuf::from("/etc/tarsnap-lifespan/protected")),
+                holds: Some(PathBuf::from("/var/lib/tarsnap-lifespan/holds.toml")),
+                ..PolicyConfig::default()
+            })
+        );
+        assert!(config.policy("weekly").is_err());
+    }
+
+    #[test]
+    fn empty_config() {
+        assert_eq!(parse_config(""), Ok(Config::default()));
+    }
+
+    #[test]
+    fn invalid_generation_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = [
+                "31D",
+                "3X",
+            ]
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 4: policy default:"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_strategy_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D"]
+            strategy = "closest"
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 3: policy default:"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_key_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D"]
+            generatoins = ["12M"]
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 3: "), "{}", err);
+        assert!(err.contains("generatoins"), "{}", err);
+        assert!(!err.contains("column"), "{}", err);
+    }
+
+    #[test]
+    fn syntax_error_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D"]
+            dry-run = yes
+            batch-size = 10
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 3: "), "{}", err);
+    }
+
+    #[test]
+    fn groups() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D"]
+            rotate-ungrouped = true
+
+            [[policy.default.group]]
+            glob = "db-*"
+            generations = ["48H", "31D"]
+
+            [[policy.default.group]]
+            name = "etc"
+            regex = "^etc-"
+            "#
+        );
+
+        let config = parse_config(source).unwrap();
+        let policy = config.policy("default").unwrap();
+        assert_eq!(policy.rotate_ungrouped, Some(true));
+        assert_eq!(
+            policy.groups,
+            vec![
+                GroupConfig {
+                    name: "db-*".to_string(),
+                    pattern: "^db\\-.*$".to_string(),
+                    generations: Some(vec!["48H".to_string(), "31D".to_string()]),
+                },
+                GroupConfig {
+                    name: "etc".to_string(),
+                    pattern: "^etc-".to_string(),
+                    generations: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn group_without_pattern_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D"]
+
+            [[policy.default.group]]
+            name = "db"
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 4: policy default:"), "{}", err);
+    }
+
+    #[test]
+    fn invalid_group_regex_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            [[policy.default.group]]
+            regex = "db-("
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 3: policy default:"), "{}", err);
+    }
+
+    #[test]
+    fn group_from_command_line() {
+        assert_eq!(
+            "db-*=48H,31D".parse(),
+            Ok(GroupConfig {
+                name: "db-*".to_string(),
+                pattern: "^db\\-.*$".to_string(),
+                generations: Some(vec!["48H".to_string(), "31D".to_string()]),
+            })
+        );
+        assert_eq!(
+            "etc-*".parse(),
+            Ok(GroupConfig {
+                name: "etc-*".to_string(),
+                pattern: "^etc\\-.*$".to_string(),
+                generations: None,
+            })
+        );
+        assert!("db-*=48X".parse::<GroupConfig>().is_err());
+    }
+
+    #[test]
+    fn invalid_timestamp_format_reports_line() {
+        let source = indoc!(
+            r#"
+            [policy.default]
+            generations = ["31D"]
+            timestamp-from-name = "%Y-%b"
+            "#
+        );
+
+        let err = parse_config(source).unwrap_err().to_string();
+        assert!(err.starts_with("line 3: policy default:"), "{}", err);
+    }
+}
@@ -0,0 +1,106 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use regex::Regex;
+use chrono::Duration;
+use error::Error;
+
+/// Parse a duration such as "48h", "30m", "1w" or "1d12h". The units are
+/// s = seconds, m = minutes, h = hours, d = days and w = weeks.
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let duration_re = Regex::new(r"^(?:\d+[smhdw])+$").unwrap();
+    let part_re = Regex::new(r"(\d+)([smhdw])").unwrap();
+    if !duration_re.is_match(s) {
+        return Err(Error::invalid_argument(s, "expected a duration such as 48h or 1d12h"));
+    }
+
+    let mut total = Duration::zero();
+    for caps in part_re.captures_iter(s) {
+        let n = caps[1]
+            .parse::<i64>()
+            .map_err(|err| Error::invalid_argument(s, err))?;
+        let unit_seconds = match &caps[2] {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => 7 * 24 * 60 * 60,
+        };
+        // chrono durations are limited to i64::MAX milliseconds
+        total = Some(n)
+            .filter(|&n| n <= i64::MAX / 1000 / unit_seconds)
+            .and_then(|n| total.checked_add(&Duration::seconds(n * unit_seconds)))
+            .ok_or_else(|| Error::invalid_argument(s, "the duration is too long"))?;
+    }
+
+    Ok(total)
+}
+
+/// Format the absolute value of a duration as days, hours and minutes,
+/// for example "1d 2h 5m".
+pub fn format_duration(duration: Duration) -> String {
+    let minutes = duration.num_minutes().abs();
+    let mut parts = Vec::new();
+    if minutes >= 24 * 60 {
+        parts.push(format!("{}d", minutes / (24 * 60)));
+    }
+    if minutes >= 60 {
+        parts.push(format!("{}h", minutes / 60 % 24));
+    }
+    parts.push(format!("{}m", minutes % 60));
+
+    parts.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_duration("48h"), Ok(Duration::hours(48)));
+        assert_eq!(parse_duration("1d12h"), Ok(Duration::hours(36)));
+        assert_eq!(parse_duration("2w"), Ok(Duration::days(14)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("12").is_err());
+        assert!(parse_duration("1x").is_err());
+    }
+
+    #[test]
+    fn parse_too_long() {
+        assert!(parse_duration("99999999999999999d").is_err());
+        assert!(parse_duration("99999999999999999999d").is_err());
+        assert!(parse_duration("15000000000w15000000000w").is_err());
+        assert_eq!(parse_duration("1000000000w"), Ok(Duration::weeks(1_000_000_000)));
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_duration(Duration::seconds(30)), "0m");
+        assert_eq!(format_duration(Duration::minutes(-75)), "1h 15m");
+        assert_eq!(format_duration(Duration::days(2) + Duration::minutes(61)), "2d 1h 1m");
+    }
+}
@@ -0,0 +1,252 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while rotating archives.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// An invalid command line argument, generation or option value.
+    InvalidArgument { arg: String, reason: String },
+    /// An invalid configuration file.
+    Config {
+        path: Option<PathBuf>,
+        line: Option<usize>,
+        message: String,
+    },
+    /// An archive listing row or an archive name that couldn't be parsed.
+    Parse { row: String, reason: String },
+    /// The tarsnap binary couldn't be executed.
+    Spawn { program: String, reason: String },
+    /// tarsnap exited with an error. `exit_code` is `None` if tarsnap was
+    /// killed by a signal.
+    Tarsnap {
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    /// The archive to be deleted doesn't exist.
+    ArchiveNotFound(String),
+    /// The archives still exist after tarsnap reported that they were
+    /// deleted.
+    NotDeleted(Vec<String>),
+    /// Deleting `count` out of `total` archives would exceed the limit
+    /// given by `--max-delete` or `--max-delete-percent`.
+    DeleteLimit {
+        count: usize,
+        total: usize,
+        limit: String,
+    },
+    /// The archive timestamps don't agree with the current time, which
+    /// suggests that the clock is wrong.
+    ClockSkew(String),
+    /// Another process holds the lock file. `pid` is its process id, if
+    /// known.
+    AlreadyRunning { path: PathBuf, pid: Option<u32> },
+    /// Reading or writing a local file, such as the lock file, the audit
+    /// log, the holds file or the metrics file, failed.
+    Io { path: PathBuf, reason: String },
+}
+
+impl Error {
+    /// The process exit code for this error:
+    ///
+    /// * 2: invalid arguments or configuration file
+    /// * 3: the archive listing couldn't be parsed
+    /// * 4: tarsnap couldn't be executed
+    /// * 5: tarsnap failed
+    /// * 6: too many archives would have been deleted
+    /// * 7: the newest archive is too far in the future or in the past
+    /// * 8: another run holds the lock
+    /// * 9: a local file couldn't be read or written
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::InvalidArgument { .. } | Error::Config { .. } => 2,
+            Error::Parse { .. } => 3,
+            Error::Spawn { .. } => 4,
+            Error::Tarsnap { .. } | Error::ArchiveNotFound(_) | Error::NotDeleted(_) => 5,
+            Error::DeleteLimit { .. } => 6,
+            Error::ClockSkew(_) => 7,
+            Error::AlreadyRunning { .. } => 8,
+            Error::Io { .. } => 9,
+        }
+    }
+
+    /// Shorthand for constructing an `InvalidArgument` error.
+    pub fn invalid_argument<A: Into<String>, R: ToString>(arg: A, reason: R) -> Error {
+        Error::InvalidArgument {
+            arg: arg.into(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Shorthand for constructing an `Io` error.
+    pub fn io<P: Into<PathBuf>, R: ToString>(path: P, reason: R) -> Error {
+        Error::Io {
+            path: path.into(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidArgument { ref arg, ref reason } => {
+                write!(f, "Invalid argument {}: {}", arg, reason)
+            }
+            Error::Config {
+                ref path,
+                line,
+                ref message,
+            } => {
+                if let Some(ref path) = *path {
+                    write!(f, "{}: ", path.display())?;
+                }
+                if let Some(line) = line {
+                    write!(f, "line {}: ", line)?;
+                }
+                write!(f, "{}", message)
+            }
+            Error::Parse { ref row, ref reason } => {
+                write!(f, "Failed to parse \"{}\": {}", row.escape_default(), reason)
+            }
+            Error::Spawn {
+                ref program,
+                ref reason,
+            } => write!(f, "Failed to execute {}: {}", program, reason),
+            Error::Tarsnap {
+                exit_code,
+                ref stderr,
+            } => {
+                match exit_code {
+                    Some(code) => write!(f, "tarsnap failed with exit code {}", code)?,
+                    None => write!(f, "tarsnap was killed by a signal")?,
+                }
+                if stderr.trim().is_empty() {
+                    Ok(())
+                } else {
+                    write!(f, ": {}", stderr.trim())
+                }
+            }
+            Error::ArchiveNotFound(ref name) => write!(f, "Archive does not exist: {}", name),
+            Error::NotDeleted(ref names) => write!(
+                f,
+                "Archives still exist after deleting them: {}",
+                names.join(", ")
+            ),
+            Error::DeleteLimit {
+                count,
+                total,
+                ref limit,
+            } => write!(
+                f,
+                "Refusing to delete {} of {} archives, the limit is {}",
+                count, total, limit
+            ),
+            Error::AlreadyRunning { ref path, pid } => {
+                write!(f, "Another run is already holding the lock {}", path.display())?;
+                match pid {
+                    Some(pid) => write!(f, " (process {})", pid),
+                    None => Ok(()),
+                }
+            }
+            Error::Io { ref path, ref reason } => write!(f, "{}: {}", path.display(), reason),
+            Error::ClockSkew(ref message) => write!(
+                f,
+                "Refusing to delete: {}. Check the clock or set the time with --now",
+                message
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_are_distinct() {
+        let config = Error::Config {
+            path: None,
+            line: Some(3),
+            message: "bad".to_string(),
+        };
+        let parse = Error::Parse {
+            row: "archive".to_string(),
+            reason: "no timestamp".to_string(),
+        };
+        let spawn = Error::Spawn {
+            program: "tarsnap".to_string(),
+            reason: "not found".to_string(),
+        };
+        let tarsnap = Error::Tarsnap {
+            exit_code: Some(1),
+            stderr: "error".to_string(),
+        };
+        let delete_limit = Error::DeleteLimit {
+            count: 10,
+            total: 12,
+            limit: "5 archives".to_string(),
+        };
+
+        assert_eq!(config.exit_code(), 2);
+        assert_eq!(parse.exit_code(), 3);
+        assert_eq!(spawn.exit_code(), 4);
+        assert_eq!(tarsnap.exit_code(), 5);
+        assert_eq!(delete_limit.exit_code(), 6);
+        assert_eq!(Error::ClockSkew("skew".to_string()).exit_code(), 7);
+        assert_eq!(Error::io("/var/log/audit.log", "No space left").exit_code(), 9);
+    }
+
+    #[test]
+    fn display_config_error() {
+        let err = Error::Config {
+            path: Some(PathBuf::from("/etc/tarsnap-lifespan.toml")),
+            line: Some(3),
+            message: "policy default: Unknown strategy".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "/etc/tarsnap-lifespan.toml: line 3: policy default: Unknown strategy"
+        );
+    }
+
+    #[test]
+    fn display_tarsnap_error() {
+        let err = Error::Tarsnap {
+            exit_code: Some(1),
+            stderr: "tarsnap: Cannot read key file\n".to_string(),
+        };
+        assert_eq!(
+            err.to_string(),
+            "tarsnap failed with exit code 1: tarsnap: Cannot read key file"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::fmt::Write;
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use duration::format_duration;
+use generation::{KeepReason, Strategy, fill_slots};
+use group::{Group, Protection, decide, group_index};
+use snapshot::Snapshot;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Explain the retention decisions in a human readable form.
+///
+/// For each group and generation, the slots are listed with their target
+/// times, the snapshots that fill them and the distance between the two.
+/// A slot that is filled by the same snapshot as an earlier slot of the
+/// generation is marked as collapsed. The snapshots that are kept only
+/// because they are the latest of their group or because of the
+/// `protection` rules, such as holds, are listed separately. The times
+/// are shown in `timezone`.
+pub fn explain(
+    groups: &[Group],
+    strategy: Strategy,
+    protection: &Protection,
+    now: &DateTime<Utc>,
+    timezone: &Tz,
+    snapshots: Vec<Snapshot>,
+) -> String {
+    let local = |t: &DateTime<Utc>| t.with_timezone(timezone).format(TIME_FORMAT);
+    let mut out = String::new();
+    writeln!(out, "Now: {}", local(now)).unwrap();
+
+    for (i, group) in groups.iter().enumerate() {
+        let members: Vec<Snapshot> = snapshots
+            .iter()
+            .filter(|x| group_index(groups, x) == Some(i))
+            .cloned()
+            .collect();
+        writeln!(out, "\nGroup {}: {} archives", group.name, members.len()).unwrap();
+
+        for gen in &group.generations {
+            writeln!(out, "  Generation {}:", gen).unwrap();
+            let slots = fill_slots(&members, gen, strategy, now);
+            if slots.is_empty() {
+                writeln!(out, "    no archives").unwrap();
+            }
+            for (j, slot) in slots.iter().enumerate() {
+                write!(out, "    slot {}: ", slot.index).unwrap();
+                if let Some(target) = slot.target {
+                    write!(out, "target {} -> ", local(&target)).unwrap();
+                }
+                write!(
+                    out,
+                    "{} ({})",
+                    slot.snapshot.name,
+                    local(&slot.snapshot.ts)
+                ).unwrap();
+                if let Some(target) = slot.target {
+                    write!(out, ", {}", format_distance(slot.snapshot.ts - target)).unwrap();
+                }
+                let earlier = slots[..j]
+                    .iter()
+                    .find(|x| x.snapshot.name == slot.snapshot.name);
+                if let Some(earlier) = earlier {
+                    write!(out, ", collapsed into slot {}", earlier.index).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+        }
+    }
+
+    let mut decisions = decide(groups, strategy, protection, now, snapshots);
+    decisions.sort_by(|a, b| a.snapshot.name.cmp(&b.snapshot.name));
+    let names = |pred: &dyn Fn(&[KeepReason]) -> bool| -> Vec<String> {
+        decisions
+            .iter()
+            .filter(|x| pred(&x.reasons))
+            .map(|x| x.snapshot.name.clone())
+            .collect()
+    };
+    let protected: Vec<String> = decisions
+        .iter()
+        .filter(|x| {
+            !x.reasons
+                .iter()
+                .any(|r| matches!(*r, KeepReason::Generation { .. }))
+                && x.reasons.iter().any(|r| {
+                    !matches!(*r, KeepReason::Latest | KeepReason::Ungrouped)
+                })
+        })
+        .map(|x| {
+            let reasons: Vec<String> = x.reasons.iter().map(|r| r.to_string()).collect();
+            format!("{} ({})", x.snapshot.name, reasons.join(", "))
+        })
+        .collect();
+    let sections = [
+        (
+            "Kept only because they are the latest of their group",
+            names(&|reasons| reasons == [KeepReason::Latest]),
+        ),
+        ("Kept only because of protection rules", protected),
+        (
+            "Kept because they don't belong to any group",
+            names(&|reasons| reasons == [KeepReason::Ungrouped]),
+        ),
+        ("Deleted", names(&|reasons| reasons.is_empty())),
+    ];
+    for &(title, ref names) in &sections {
+        writeln!(out, "\n{}:", title).unwrap();
+        if names.is_empty() {
+            writeln!(out, "  none").unwrap();
+        }
+        for name in names {
+            writeln!(out, "  {}", name).unwrap();
+        }
+    }
+
+    out
+}
+
+// Format the distance of a snapshot from its target, for example
+// "1d 2h 5m before the target".
+fn format_distance(distance: Duration) -> String {
+    if distance.num_minutes() == 0 {
+        "at the target".to_string()
+    } else if distance < Duration::zero() {
+        format!("{} before the target", format_duration(distance))
+    } else {
+        format!("{} after the target", format_duration(distance))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+    use chrono_tz::Tz;
+    use generation::{Generation, IntervalMode, Unit};
+
+    fn snapshot(name: &str, day: u32, hour: u32) -> Snapshot {
+        Snapshot {
+            name: name.to_string(),
+            ts: Utc.ymd(2018, 7, day).and_hms(hour, 0, 0),
+        }
+    }
+
+    #[test]
+    fn distance() {
+        assert_eq!(format_distance(Duration::seconds(30)), "at the target");
+        assert_eq!(format_distance(Duration::minutes(-5)), "5m before the target");
+        assert_eq!(
+            format_distance(Duration::days(2) + Duration::minutes(61)),
+            "2d 1h 1m after the target"
+        );
+    }
+
+    #[test]
+    fn explain_nearest() {
+        let groups = vec![
+            Group {
+                name: "db".to_string(),
+                pattern: Regex::new("^db-").unwrap(),
+                generations: vec![
+                    Generation {
+                        unit: Unit::Day,
+                        count: 3,
+                        mode: IntervalMode::Calendar,
+                        timezone: Tz::UTC,
+                    },
+                ],
+            },
+        ];
+        let snapshots = vec![
+            snapshot("db-01", 1, 0),
+            snapshot("db-18", 18, 23),
+            snapshot("db-19", 19, 22),
+            snapshot("etc-01", 1, 0),
+        ];
+        let now = Utc.ymd(2018, 7, 20).and_hms(0, 0, 0);
+
+        assert_eq!(
+            explain(&groups, Strategy::Nearest, &Protection::default(), &now, &Tz::UTC, snapshots),
+            indoc!(
+                "
+                Now: 2018-07-20 00:00:00
+
+                Group db: 3 archives
+                  Generation 3D:
+                    slot 1: target 2018-07-19 00:00:00 -> db-18 (2018-07-18 23:00:00), 1h 0m before the target
+                    slot 2: target 2018-07-18 00:00:00 -> db-18 (2018-07-18 23:00:00), 23h 0m after the target, collapsed into slot 1
+                    slot 3: target 2018-07-17 00:00:00 -> db-18 (2018-07-18 23:00:00), 1d 23h 0m after the target, collapsed into slot 1
+
+                Kept only because they are the latest of their group:
+                  db-19
+
+                Kept only because of protection rules:
+                  none
+
+                Kept because they don't belong to any group:
+                  etc-01
+
+                Deleted:
+                  db-01
+                "
+            )
+        );
+    }
+}
@@ -0,0 +1,1465 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use regex::Regex;
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use error::Error;
+use group::{Group, Protection, select_group_snapshots_to_delete};
+use snapshot::{Snapshot, SnapshotTimestamp};
+use timestamp::from_local;
+
+/// The longest time span a generation may cover. This keeps the targets
+/// within the dates chrono can represent and bounds the number of slots.
+const MAX_SPAN_YEARS: usize = 10_000;
+
+/// The length of one rotation step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Hour,
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl Unit {
+    /// The letter of the unit in generation arguments.
+    pub fn letter(&self) -> char {
+        match *self {
+            Unit::Hour => 'H',
+            Unit::Day => 'D',
+            Unit::Week => 'W',
+            Unit::Month => 'M',
+            Unit::Year => 'Y',
+        }
+    }
+}
+
+/// How months and years are measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntervalMode {
+    /// Step back whole calendar months and years.
+    Calendar,
+    /// A month is 30 days and a year is 365 days.
+    Fixed,
+}
+
+/// One rotation generation: keep `count` snapshots that are one `unit`
+/// apart from each other.
+///
+/// Days, weeks, months and years are measured on the wall clock of
+/// `timezone`, so a daily snapshot stays at the same local time of day
+/// across daylight saving time transitions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Generation {
+    pub unit: Unit,
+    pub count: usize,
+    pub mode: IntervalMode,
+    pub timezone: Tz,
+}
+
+impl Generation {
+    /// The target time of the `i`th snapshot of this generation, that is
+    /// `now - i*interval`, or `None` if the target is before the earliest
+    /// representable date.
+    pub fn target(&self, now: &DateTime<Utc>, i: usize) -> Option<DateTime<Utc>> {
+        let steps = i64::try_from(i).ok()?;
+        let local = now.with_timezone(&self.timezone).naive_local();
+        let days_back = |days: i64| {
+            sub_days(&local, days.checked_mul(steps)?).map(|t| from_local(&self.timezone, &t))
+        };
+        match (self.unit, self.mode) {
+            // Hours are not affected by daylight saving time
+            (Unit::Hour, _) => sub_days(&now.naive_utc(), steps / 24)
+                .and_then(|t| t.checked_sub_signed(Duration::hours(steps % 24)))
+                .map(|t| DateTime::from_utc(t, Utc)),
+            (Unit::Day, _) => days_back(1),
+            (Unit::Week, _) => days_back(7),
+            (Unit::Month, IntervalMode::Fixed) => days_back(30),
+            (Unit::Year, IntervalMode::Fixed) => days_back(365),
+            (Unit::Month, IntervalMode::Calendar) => {
+                sub_months(&local, steps).map(|t| from_local(&self.timezone, &t))
+            }
+            (Unit::Year, IntervalMode::Calendar) => steps
+                .checked_mul(12)
+                .and_then(|months| sub_months(&local, months))
+                .map(|t| from_local(&self.timezone, &t)),
+        }
+    }
+
+    /// The largest count whose generation spans at most `MAX_SPAN_YEARS`.
+    fn max_count(unit: Unit) -> usize {
+        MAX_SPAN_YEARS * match unit {
+            Unit::Hour => 366 * 24,
+            Unit::Day => 366,
+            Unit::Week => 53,
+            Unit::Month => 12,
+            Unit::Year => 1,
+        }
+    }
+}
+
+impl fmt::Display for Generation {
+    /// Formats the generation as a generation argument, for example "31D".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.count, self.unit.letter())
+    }
+}
+
+// Go back by whole days. Returns None if the result is out of range.
+fn sub_days(t: &NaiveDateTime, days: i64) -> Option<NaiveDateTime> {
+    // chrono dates span less than 200 million days. Larger values would
+    // overflow Duration.
+    if days.abs() > 200_000_000 {
+        return None;
+    }
+    t.checked_sub_signed(Duration::days(days))
+}
+
+// Go back by whole calendar months. The day of month is clamped to the
+// length of the target month, for example March 31 minus one month is
+// February 28 (or 29). Returns None if the result is out of range.
+fn sub_months(t: &NaiveDateTime, months: i64) -> Option<NaiveDateTime> {
+    let month_index = (i64::from(t.year()) * 12 + i64::from(t.month0())).checked_sub(months)?;
+    let year = i32::try_from(month_index.div_euclid(12)).ok()?;
+    let month = month_index.rem_euclid(12) as u32 + 1;
+
+    (1..(t.day() + 1))
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .map(|date| date.and_time(t.time()))
+}
+
+impl fmt::Display for IntervalMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IntervalMode::Calendar => write!(f, "calendar"),
+            IntervalMode::Fixed => write!(f, "fixed"),
+        }
+    }
+}
+
+/// How the snapshots of a generation are selected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Keep the snapshot closest to each target time `now - i*interval`.
+    Nearest,
+    /// Group the snapshots into calendar buckets (hour, day, ISO week,
+    /// month or year) and keep the oldest snapshot of each bucket.
+    BucketOldest,
+    /// Like `BucketOldest` but keep the newest snapshot of each bucket.
+    BucketNewest,
+}
+
+impl fmt::Display for Strategy {
+    /// Formats the strategy as it is given on the command line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Strategy::Nearest => write!(f, "nearest"),
+            Strategy::BucketOldest => write!(f, "bucket-oldest"),
+            Strategy::BucketNewest => write!(f, "bucket-newest"),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Strategy, Error> {
+        match s {
+            "nearest" => Ok(Strategy::Nearest),
+            "bucket-oldest" => Ok(Strategy::BucketOldest),
+            "bucket-newest" => Ok(Strategy::BucketNewest),
+            _ => Err(Error::invalid_argument(s, "unknown strategy")),
+        }
+    }
+}
+
+/// Parse generation arguments such as "31D", "10W" or "12M".
+///
+/// The accepted interval letters are H = hourly, D = daily, W = weekly,
+/// M = monthly and Y = yearly. `mode` selects whether months and years
+/// are calendar months and years or fixed durations, and `timezone` is
+/// the zone whose calendar the intervals follow.
+pub fn parse_generations(
+    generation_args: Vec<String>,
+    mode: IntervalMode,
+    timezone: Tz,
+) -> Result<Vec<Generation>, Error> {
+    let generation_re = Regex::new(r"^(\d+)([HDWMY])$").unwrap();
+
+    generation_args
+        .iter()
+        .map(|arg| match generation_re.captures(arg) {
+            Some(caps) => {
+                let unit = match &caps[2] {
+                    "H" => Unit::Hour,
+                    "D" => Unit::Day,
+                    "W" => Unit::Week,
+                    "M" => Unit::Month,
+                    _ => Unit::Year,
+                };
+                let count = caps[1]
+                    .parse::<usize>()
+                    .map_err(|err| Error::invalid_argument(arg.as_str(), err))?;
+                if count > Generation::max_count(unit) {
+                    return Err(Error::invalid_argument(
+                        arg.as_str(),
+                        format!("a generation can span at most {} years", MAX_SPAN_YEARS),
+                    ));
+                }
+                Ok(Generation { unit, count, mode, timezone })
+            }
+            None => Err(Error::invalid_argument(arg.as_str(), "expected <number><H|D|W|M|Y>")),
+        })
+        .collect()
+}
+
+/// Why a snapshot is kept.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "rule", rename_all = "kebab-case")]
+pub enum KeepReason {
+    /// The snapshot fills the slot number `slot` of a generation, such as
+    /// "31D".
+    Generation { generation: String, slot: usize },
+    /// The latest snapshot is always kept.
+    Latest,
+    /// The snapshot is one of the `--keep-last` most recent snapshots.
+    /// The most recent snapshot has the position 1.
+    KeepLast { position: usize },
+    /// The snapshot is younger than `--min-age`.
+    MinAge,
+    /// The snapshot name matches a protected pattern.
+    Protected { pattern: String },
+    /// The snapshot is listed in the file of protected archives.
+    Pinned,
+    /// The snapshot has a hold.
+    Hold {
+        reason: Option<String>,
+        expires: Option<DateTime<Utc>>,
+    },
+    /// The snapshot doesn't belong to any group.
+    Ungrouped,
+    /// The snapshot name has no timestamp and `--timestamp-fallback skip`
+    /// leaves it out of the rotation.
+    NoTimestamp,
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeepReason::Generation {
+                ref generation,
+                slot,
+            } => write!(f, "{} slot {}", generation, slot),
+            KeepReason::Latest => write!(f, "latest"),
+            KeepReason::KeepLast { position } => write!(f, "keep-last {}", position),
+            KeepReason::MinAge => write!(f, "min-age"),
+            KeepReason::Protected { ref pattern } => write!(f, "protected by {}", pattern),
+            KeepReason::Pinned => write!(f, "pinned"),
+            KeepReason::Hold { ref reason, .. } => match *reason {
+                Some(ref reason) => write!(f, "hold: {}", reason),
+                None => write!(f, "hold"),
+            },
+            KeepReason::Ungrouped => write!(f, "ungrouped"),
+            KeepReason::NoTimestamp => write!(f, "timestamp not found in name"),
+        }
+    }
+}
+
+/// A slot of a generation and the snapshot that fills it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slot<T> {
+    /// The slot number. The most recent slot is 1.
+    pub index: usize,
+    /// The target time `now - index*interval`. Only the nearest strategy
+    /// has target times.
+    pub target: Option<DateTime<Utc>>,
+    pub snapshot: T,
+}
+
+/// Return the names of the snapshots that are not needed by any of the
+/// generations and not kept by `protection`.
+pub fn select_snapshots_to_delete(
+    generations: &[Generation],
+    strategy: Strategy,
+    protection: &Protection,
+    now: &DateTime<Utc>,
+    snapshots: Vec<Snapshot>,
+) -> Vec<String> {
+    let all = [Group::all(generations.to_vec())];
+    select_group_snapshots_to_delete(&all, strategy, protection, now, snapshots)
+}
+
+/// Return the names of the snapshots that should be kept. The latest
+/// snapshot is always kept.
+pub fn keep_generations(
+    snapshots: &[Snapshot],
+    generations: &[Generation],
+    strategy: Strategy,
+    now: &DateTime<Utc>,
+) -> HashSet<String> {
+    keep_reasons(snapshots, generations, strategy, now)
+        .into_keys()
+        .collect()
+}
+
+/// Return the snapshots that should be kept and the reasons for keeping
+/// each of them.
+pub fn keep_reasons(
+    snapshots: &[Snapshot],
+    generations: &[Generation],
+    strategy: Strategy,
+    now: &DateTime<Utc>,
+) -> HashMap<String, Vec<KeepReason>> {
+    let mut reasons: HashMap<String, Vec<KeepReason>> = HashMap::new();
+    for gen in generations {
+        for slot in fill_slots(snapshots, gen, strategy, now) {
+            reasons
+                .entry(slot.snapshot.name)
+                .or_default()
+                .push(KeepReason::Generation {
+                    generation: gen.to_string(),
+                    slot: slot.index,
+                });
+        }
+    }
+
+    // Always keep the latest snapshot
+    let maybe_latest = snapshots.iter().max_by_key(|x| x.timestamp()).map(|x| {
+        x.name.clone()
+    });
+    if let Some(latest) = maybe_latest {
+        reasons.entry(latest).or_default().push(KeepReason::Latest);
+    }
+
+    reasons
+}
+
+/// Fill the slots of a generation with snapshots.
+///
+/// With the nearest strategy, the same snapshot may fill several slots.
+/// The slots are ordered from the most recent to the oldest.
+pub fn fill_slots<T: SnapshotTimestamp + Clone>(
+    timestamps: &[T],
+    generation: &Generation,
+    strategy: Strategy,
+    now: &DateTime<Utc>,
+) -> Vec<Slot<T>> {
+    match strategy {
+        Strategy::Nearest => nearest_slots(timestamps, generation, now),
+        Strategy::BucketOldest => bucket_slots(timestamps, generation, true),
+        Strategy::BucketNewest => bucket_slots(timestamps, generation, false),
+    }
+}
+
+/// Select the snapshots that belong to a generation.
+///
+/// For each of the target times `now - i*interval`, i = 1..count, the
+/// snapshot closest to the target is selected. The result is sorted by
+/// timestamp and contains no duplicates.
+pub fn filter_by_generation<T: SnapshotTimestamp + Clone + PartialEq>(
+    timestamps: &[T],
+    generation: &Generation,
+    now: &DateTime<Utc>,
+) -> Vec<T> {
+    let mut selected = nearest_slots(timestamps, generation, now).into_iter().fold(
+        Vec::new(),
+        |mut acc, slot| {
+            if !acc.contains(&slot.snapshot) {
+                acc.push(slot.snapshot);
+            }
+
+            acc
+        },
+    );
+
+    selected.sort_unstable_by_key(|a| a.timestamp());
+
+    selected
+}
+
+fn nearest_slots<T: SnapshotTimestamp + Clone>(
+    timestamps: &[T],
+    generation: &Generation,
+    now: &DateTime<Utc>,
+) -> Vec<Slot<T>> {
+    let oldest = match timestamps.iter().map(|t| t.timestamp()).min() {
+        Some(oldest) => oldest,
+        None => return Vec::new(),
+    };
+
+    // Once a target is older than the oldest snapshot, the rest of the
+    // slots would be filled by the oldest snapshot, too
+    let mut slots = Vec::new();
+    for i in 1..=generation.count {
+        let step = match generation.target(now, i) {
+            Some(step) => step,
+            None => break,
+        };
+        let closest = timestamps.iter().min_by_key(|t| {
+            (t.timestamp().timestamp() - step.timestamp()).abs()
+        });
+        if let Some(x) = closest {
+            slots.push(Slot {
+                index: i,
+                target: Some(step),
+                snapshot: x.clone(),
+            });
+        }
+        if step < oldest {
+            break;
+        }
+    }
+
+    slots
+}
+
+/// Select the snapshots that belong to a generation by grouping them into
+/// calendar buckets.
+///
+/// The snapshots are grouped by the hour, day, ISO week, month or year
+/// they were taken in. One snapshot, the oldest if `keep_oldest` is true
+/// and otherwise the newest, is selected from each of the `count` latest
+/// buckets that contain snapshots. Months and years are always calendar
+/// months and years. The result is sorted by timestamp.
+pub fn filter_by_bucket<T: SnapshotTimestamp + Clone>(
+    timestamps: &[T],
+    generation: &Generation,
+    keep_oldest: bool,
+) -> Vec<T> {
+    let mut selected: Vec<T> = bucket_slots(timestamps, generation, keep_oldest)
+        .into_iter()
+        .map(|slot| slot.snapshot)
+        .collect();
+
+    selected.sort_unstable_by_key(|a| a.timestamp());
+
+    selected
+}
+
+fn bucket_slots<T: SnapshotTimestamp + Clone>(
+    timestamps: &[T],
+    generation: &Generation,
+    keep_oldest: bool,
+) -> Vec<Slot<T>> {
+    let mut newest_first: Vec<&T> = timestamps.iter().collect();
+    newest_first.sort_by_key(|t| Reverse(t.timestamp()));
+
+    let mut buckets: Vec<Vec<&T>> = Vec::new();
+    let mut last_key = None;
+    for t in newest_first {
+        let key = bucket_key(&t.timestamp(), generation.unit, &generation.timezone);
+        if last_key != Some(key) {
+            if buckets.len() == generation.count {
+                break;
+            }
+            buckets.push(Vec::new());
+            last_key = Some(key);
+        }
+        if let Some(members) = buckets.last_mut() {
+            members.push(t);
+        }
+    }
+
+    buckets
+        .iter()
+        .filter_map(|members| if keep_oldest {
+            members.last()
+        } else {
+            members.first()
+        })
+        .enumerate()
+        .map(|(i, t)| Slot {
+            index: i + 1,
+            target: None,
+            snapshot: (*t).clone(),
+        })
+        .collect()
+}
+
+// Identifies the calendar bucket of a timestamp in the given time zone.
+// Later buckets have larger keys.
+fn bucket_key(t: &DateTime<Utc>, unit: Unit, timezone: &Tz) -> i64 {
+    let t = t.with_timezone(timezone);
+    match unit {
+        Unit::Hour => {
+            // Only the part of the UTC offset that is not whole hours
+            // matters, so the hour that is repeated when daylight saving
+            // time ends is two separate buckets.
+            let offset = i64::from(t.offset().fix().local_minus_utc());
+            (t.timestamp() + offset.rem_euclid(3600)).div_euclid(3600)
+        }
+        Unit::Day => i64::from(t.num_days_from_ce()),
+        Unit::Week => {
+            let week = t.iso_week();
+            i64::from(week.year()) * 100 + i64::from(week.week())
+        }
+        Unit::Month => i64::from(t.year()) * 12 + i64::from(t.month0()),
+        Unit::Year => i64::from(t.year()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct TestSnapshot {
+        ts: DateTime<Utc>,
+    }
+
+    impl SnapshotTimestamp for TestSnapshot {
+        fn timestamp(&self) -> DateTime<Utc> {
+            self.ts
+        }
+    }
+
+    fn test_generations() -> Vec<Generation> {
+        vec![
+            Generation {
+                unit: Unit::Day,
+                count: 6,
+                mode: IntervalMode::Fixed,
+                timezone: Tz::UTC,
+            },
+            Generation {
+                unit: Unit::Month,
+                count: 4,
+                mode: IntervalMode::Fixed,
+                timezone: Tz::UTC,
+            },
+            Generation {
+                unit: Unit::Year,
+                count: 1,
+                mode: IntervalMode::Fixed,
+                timezone: Tz::UTC,
+            },
+        ]
+    }
+
+    fn hour_generation(n: usize) -> Generation {
+        Generation {
+            unit: Unit::Hour,
+            count: n,
+            mode: IntervalMode::Fixed,
+            timezone: Tz::UTC,
+        }
+    }
+
+    fn day_generation(n: usize) -> Generation {
+        Generation {
+            unit: Unit::Day,
+            count: n,
+            mode: IntervalMode::Fixed,
+            timezone: Tz::UTC,
+        }
+    }
+
+    fn month_generation(n: usize) -> Generation {
+        Generation {
+            unit: Unit::Month,
+            count: n,
+            mode: IntervalMode::Fixed,
+            timezone: Tz::UTC,
+        }
+    }
+
+    fn utc_midnight(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn filter_empty() {
+        let snapshots: Vec<Snapshot> = vec![];
+        let now = Utc.ymd(2018, 7, 14).and_hms(14, 0, 0);
+        let filtered = filter_by_generation(&snapshots, &day_generation(6), &now);
+
+        assert_eq!(filtered, []);
+    }
+
+    #[test]
+    fn filter_one() {
+        let snapshots = vec![TestSnapshot { ts: utc_midnight(2018, 7, 10) }];
+        let now = Utc.ymd(2018, 7, 14).and_hms(14, 0, 0);
+        let filtered = filter_by_generation(&snapshots, &day_generation(6), &now);
+
+        assert_eq!(filtered, snapshots);
+    }
+
+    #[test]
+    fn filter_even_intervals() {
+        let snapshots = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 15).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 15).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(12, 0, 0) },
+        ];
+        let now = Utc.ymd(2018, 7, 17).and_hms(14, 0, 0);
+        let expected = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 15).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(12, 0, 0) },
+        ];
+
+        let filtered = filter_by_generation(&snapshots, &day_generation(3), &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn filter_uneven_intervals() {
+        let snapshots = vec![
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 18) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 27) },
+            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
+        ];
+        let now = utc_midnight(2018, 4, 5);
+        let expected = vec![
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+        ];
+
+        let filtered = filter_by_generation(&snapshots, &month_generation(4), &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn filter_hourly_intervals() {
+        let snapshots = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 1, 1).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 1, 1).and_hms(2, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 1).and_hms(13, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 25, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(1, 10, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(2, 30, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(2, 40, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 15, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 20, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(6, 15, 0) },
+        ];
+        let now = Utc.ymd(2018, 4, 2).and_hms(10, 30, 0);
+        let expected = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 4, 1).and_hms(13, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 25, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(1, 10, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(2, 30, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 20, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(6, 15, 0) },
+        ];
+
+        let filtered = filter_by_generation(&snapshots, &hour_generation(24), &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn generation_large_count() {
+        let snapshots = vec![
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
+        ];
+        let generation = Generation {
+            unit: Unit::Month,
+            count: 99,
+            mode: IntervalMode::Fixed,
+            timezone: Tz::UTC,
+        };
+        let now = utc_midnight(2018, 4, 5);
+        let expected = vec![
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+        ];
+
+        let filtered = filter_by_generation(&snapshots, &generation, &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn generation_huge_count() {
+        let snapshots = vec![
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
+        ];
+        let now = utc_midnight(2018, 4, 5);
+
+        for unit in &[Unit::Hour, Unit::Day, Unit::Week, Unit::Month] {
+            let generation = Generation {
+                unit: *unit,
+                count: usize::MAX,
+                mode: IntervalMode::Calendar,
+                timezone: Tz::UTC,
+            };
+
+            let filtered = filter_by_generation(&snapshots, &generation, &now);
+            assert_eq!(filtered, snapshots);
+        }
+    }
+
+    #[test]
+    fn target_out_of_range() {
+        let now = utc_midnight(2018, 4, 5);
+        for unit in &[Unit::Hour, Unit::Day, Unit::Week, Unit::Month, Unit::Year] {
+            for mode in &[IntervalMode::Calendar, IntervalMode::Fixed] {
+                let generation = Generation {
+                    unit: *unit,
+                    count: 1,
+                    mode: *mode,
+                    timezone: Tz::UTC,
+                };
+
+                assert_eq!(generation.target(&now, 3_000_000_000), None);
+                assert_eq!(generation.target(&now, usize::MAX), None);
+            }
+        }
+    }
+
+    #[test]
+    fn generation_uneven() {
+        let snapshots = vec![
+            TestSnapshot { ts: utc_midnight(2017, 10, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 18) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 27) },
+            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
+        ];
+        let generation = Generation {
+            unit: Unit::Month,
+            count: 3,
+            mode: IntervalMode::Fixed,
+            timezone: Tz::UTC,
+        };
+        let now = utc_midnight(2018, 4, 5);
+        let expected = vec![
+            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+        ];
+
+        let filtered = filter_by_generation(&snapshots, &generation, &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn zero_count_generation() {
+        let snapshots = vec![
+            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 5, 1) },
+            TestSnapshot { ts: utc_midnight(2018, 6, 1) },
+        ];
+        let generation = Generation {
+            unit: Unit::Month,
+            count: 0,
+            mode: IntervalMode::Fixed,
+            timezone: Tz::UTC,
+        };
+        let now = Utc.ymd(2018, 8, 1).and_hms(12, 0, 0);
+
+        let filtered = filter_by_generation(&snapshots, &generation, &now);
+        assert_eq!(filtered, Vec::new());
+    }
+
+    #[test]
+    fn generations_empty() {
+        let snapshots = vec![];
+        let now = Utc.ymd(2018, 8, 1).and_hms(12, 0, 0);
+
+        let filtered = keep_generations(&snapshots, &test_generations(), Strategy::Nearest, &now);
+        assert_eq!(filtered, HashSet::new());
+    }
+
+    #[test]
+    fn generations_1() {
+        let snapshots = vec![
+            Snapshot {
+                name: "two_years_ago".to_string(),
+                ts: utc_midnight(2016, 6, 1),
+            },
+            Snapshot {
+                name: "last_year".to_string(),
+                ts: utc_midnight(2017, 6, 1),
+            },
+            Snapshot {
+                name: "jan_1".to_string(),
+                ts: utc_midnight(2018, 1, 1),
+            },
+            Snapshot {
+                name: "feb_1".to_string(),
+                ts: utc_midnight(2018, 2, 1),
+            },
+            Snapshot {
+                name: "feb_27".to_string(),
+                ts: utc_midnight(2018, 2, 27),
+            },
+            Snapshot {
+                name: "feb_28".to_string(),
+                ts: utc_midnight(2018, 2, 28),
+            },
+            Snapshot {
+                name: "mar_1".to_string(),
+                ts: utc_midnight(2018, 3, 1),
+            },
+            Snapshot {
+                name: "apr_1".to_string(),
+                ts: utc_midnight(2018, 4, 1),
+            },
+            Snapshot {
+                name: "may_1".to_string(),
+                ts: utc_midnight(2018, 5, 1),
+            },
+            Snapshot {
+                name: "jun_1".to_string(),
+                ts: utc_midnight(2018, 6, 1),
+            },
+            Snapshot {
+                name: "jun_2".to_string(),
+                ts: utc_midnight(2018, 6, 2),
+            },
+            Snapshot {
+                name: "jun_3".to_string(),
+                ts: utc_midnight(2018, 6, 3),
+            },
+            Snapshot {
+                name: "jun_4".to_string(),
+                ts: utc_midnight(2018, 6, 4),
+            },
+            Snapshot {
+                name: "jun_5".to_string(),
+                ts: utc_midnight(2018, 6, 5),
+            },
+            Snapshot {
+                name: "jun_6".to_string(),
+                ts: utc_midnight(2018, 6, 6),
+            },
+        ];
+        let now = Utc.ymd(2018, 6, 6).and_hms(16, 0, 0);
+
+        let expected: HashSet<String> = [
+            "last_year".to_string(),
+            "feb_1".to_string(),
+            "mar_1".to_string(),
+            "apr_1".to_string(),
+            "may_1".to_string(),
+            "jun_1".to_string(),
+            "jun_2".to_string(),
+            "jun_3".to_string(),
+            "jun_4".to_string(),
+            "jun_5".to_string(),
+            "jun_6".to_string(),
+        ].iter()
+            .cloned()
+            .collect();
+
+        let filtered = keep_generations(&snapshots, &test_generations(), Strategy::Nearest, &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn generations_2() {
+        let snapshots = vec![
+            Snapshot {
+                name: "two_years_ago".to_string(),
+                ts: utc_midnight(2016, 6, 1),
+            },
+            Snapshot {
+                name: "last_year".to_string(),
+                ts: utc_midnight(2017, 6, 1),
+            },
+            Snapshot {
+                name: "jan_1".to_string(),
+                ts: utc_midnight(2018, 1, 1),
+            },
+            Snapshot {
+                name: "feb_1".to_string(),
+                ts: utc_midnight(2018, 2, 1),
+            },
+            Snapshot {
+                name: "mar_1".to_string(),
+                ts: utc_midnight(2018, 3, 1),
+            },
+            Snapshot {
+                name: "apr_1".to_string(),
+                ts: utc_midnight(2018, 4, 1),
+            },
+            Snapshot {
+                name: "may_1".to_string(),
+                ts: utc_midnight(2018, 5, 1),
+            },
+            Snapshot {
+                name: "jun_6".to_string(),
+                ts: utc_midnight(2018, 6, 6),
+            },
+            Snapshot {
+                name: "jun_7".to_string(),
+                ts: utc_midnight(2018, 6, 7),
+            },
+            Snapshot {
+                name: "jun_8".to_string(),
+                ts: utc_midnight(2018, 6, 8),
+            },
+            Snapshot {
+                name: "jun_9".to_string(),
+                ts: utc_midnight(2018, 6, 9),
+            },
+            Snapshot {
+                name: "jun_10".to_string(),
+                ts: utc_midnight(2018, 6, 10),
+            },
+            Snapshot {
+                name: "jun_11".to_string(),
+                ts: utc_midnight(2018, 6, 11),
+            },
+            Snapshot {
+                name: "jun_12".to_string(),
+                ts: utc_midnight(2018, 6, 12),
+            },
+        ];
+        let now = Utc.ymd(2018, 6, 12).and_hms(16, 0, 0);
+
+        let expected: HashSet<String> = [
+            "last_year".to_string(),
+            "feb_1".to_string(),
+            "mar_1".to_string(),
+            "apr_1".to_string(),
+            "may_1".to_string(),
+            "jun_7".to_string(),
+            "jun_8".to_string(),
+            "jun_9".to_string(),
+            "jun_10".to_string(),
+            "jun_11".to_string(),
+            "jun_12".to_string(),
+        ].iter()
+            .cloned()
+            .collect();
+
+        let filtered = keep_generations(&snapshots, &test_generations(), Strategy::Nearest, &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn generations_3() {
+        let snapshots = vec![
+            Snapshot {
+                name: "two_years_ago".to_string(),
+                ts: utc_midnight(2016, 6, 1),
+            },
+            Snapshot {
+                name: "last_year".to_string(),
+                ts: utc_midnight(2017, 6, 1),
+            },
+            Snapshot {
+                name: "jan_1".to_string(),
+                ts: utc_midnight(2018, 1, 1),
+            },
+            Snapshot {
+                name: "feb_1".to_string(),
+                ts: utc_midnight(2018, 2, 1),
+            },
+            Snapshot {
+                name: "mar_1".to_string(),
+                ts: utc_midnight(2018, 3, 1),
+            },
+            Snapshot {
+                name: "apr_1".to_string(),
+                ts: utc_midnight(2018, 4, 1),
+            },
+            Snapshot {
+                name: "may_1".to_string(),
+                ts: utc_midnight(2018, 5, 1),
+            },
+            Snapshot {
+                name: "jun_20".to_string(),
+                ts: utc_midnight(2018, 6, 20),
+            },
+            Snapshot {
+                name: "jun_21".to_string(),
+                ts: utc_midnight(2018, 6, 21),
+            },
+            Snapshot {
+                name: "jun_22".to_string(),
+                ts: utc_midnight(2018, 6, 22),
+            },
+            Snapshot {
+                name: "jun_23".to_string(),
+                ts: utc_midnight(2018, 6, 23),
+            },
+            Snapshot {
+                name: "jun_24".to_string(),
+                ts: utc_midnight(2018, 6, 24),
+            },
+            Snapshot {
+                name: "jun_25".to_string(),
+                ts: utc_midnight(2018, 6, 25),
+            },
+            Snapshot {
+                name: "jun_26".to_string(),
+                ts: utc_midnight(2018, 6, 26),
+            },
+        ];
+        let now = Utc.ymd(2018, 6, 26).and_hms(16, 0, 0);
+
+        let expected: HashSet<String> = [
+            "last_year".to_string(),
+            "mar_1".to_string(),
+            "apr_1".to_string(),
+            "may_1".to_string(),
+            "jun_20".to_string(),
+            "jun_21".to_string(),
+            "jun_22".to_string(),
+            "jun_23".to_string(),
+            "jun_24".to_string(),
+            "jun_25".to_string(),
+            "jun_26".to_string(),
+        ].iter()
+            .cloned()
+            .collect();
+
+        let filtered = keep_generations(&snapshots, &test_generations(), Strategy::Nearest, &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn generations_after_long_break() {
+        let snapshots = vec![
+            Snapshot {
+                name: "jan_1".to_string(),
+                ts: utc_midnight(2018, 1, 1),
+            },
+            Snapshot {
+                name: "feb_1".to_string(),
+                ts: utc_midnight(2018, 2, 1),
+            },
+            Snapshot {
+                name: "mar_1".to_string(),
+                ts: utc_midnight(2018, 3, 1),
+            },
+        ];
+        let now = Utc.ymd(2018, 12, 1).and_hms(12, 0, 0);
+        let expected: HashSet<String> = ["jan_1".to_string(), "mar_1".to_string()]
+            .iter()
+            .cloned()
+            .collect();
+
+        let filtered = keep_generations(&snapshots, &test_generations(), Strategy::Nearest, &now);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn parse_valid_generations() {
+        let args = vec!["31D".to_string(), "10W".to_string(), "12M".to_string()];
+        let expected = vec![
+            Generation {
+                unit: Unit::Day,
+                count: 31,
+                mode: IntervalMode::Calendar,
+                timezone: Tz::UTC,
+            },
+            Generation {
+                unit: Unit::Week,
+                count: 10,
+                mode: IntervalMode::Calendar,
+                timezone: Tz::UTC,
+            },
+            Generation {
+                unit: Unit::Month,
+                count: 12,
+                mode: IntervalMode::Calendar,
+                timezone: Tz::UTC,
+            },
+        ];
+
+        assert_eq!(parse_generations(args, IntervalMode::Calendar, Tz::UTC), Ok(expected));
+    }
+
+    #[test]
+    fn parse_invalid_generation() {
+        let args = vec!["31D".to_string(), "10X".to_string()];
+        assert!(parse_generations(args, IntervalMode::Calendar, Tz::UTC).is_err());
+    }
+
+    #[test]
+    fn parse_too_large_count() {
+        let args = vec!["99999999999999999999999D".to_string()];
+        assert!(parse_generations(args, IntervalMode::Calendar, Tz::UTC).is_err());
+    }
+
+    #[test]
+    fn parse_too_long_span() {
+        for arg in &["3000000Y", "99999999M", "4000000000D", "99999999999H"] {
+            match parse_generations(vec![arg.to_string()], IntervalMode::Calendar, Tz::UTC) {
+                Err(Error::InvalidArgument { .. }) => (),
+                other => panic!("{}: {:?}", arg, other),
+            }
+        }
+
+        let args = vec!["10000Y".to_string(), "87840000H".to_string()];
+        assert!(parse_generations(args, IntervalMode::Calendar, Tz::UTC).is_ok());
+    }
+
+    #[test]
+    fn calendar_month_targets() {
+        let generation = Generation {
+            unit: Unit::Month,
+            count: 3,
+            mode: IntervalMode::Calendar,
+            timezone: Tz::UTC,
+        };
+        let now = Utc.ymd(2018, 5, 31).and_hms(12, 0, 0);
+
+        assert_eq!(generation.target(&now, 1), Some(Utc.ymd(2018, 4, 30).and_hms(12, 0, 0)));
+        assert_eq!(generation.target(&now, 3), Some(Utc.ymd(2018, 2, 28).and_hms(12, 0, 0)));
+        assert_eq!(generation.target(&now, 5), Some(Utc.ymd(2017, 12, 31).and_hms(12, 0, 0)));
+        assert_eq!(generation.target(&now, 17), Some(Utc.ymd(2016, 12, 31).and_hms(12, 0, 0)));
+    }
+
+    #[test]
+    fn calendar_year_targets_leap_day() {
+        let generation = Generation {
+            unit: Unit::Year,
+            count: 4,
+            mode: IntervalMode::Calendar,
+            timezone: Tz::UTC,
+        };
+        let now = utc_midnight(2016, 2, 29);
+
+        assert_eq!(generation.target(&now, 1), Some(utc_midnight(2015, 2, 28)));
+        assert_eq!(generation.target(&now, 4), Some(utc_midnight(2012, 2, 29)));
+    }
+
+    #[test]
+    fn calendar_months_keep_first_of_month() {
+        // Monthly snapshots on the first day of each month. Fixed 30 day
+        // months drift away from the first of the month after a year.
+        let snapshots: Vec<TestSnapshot> = (1..13)
+            .map(|month| TestSnapshot { ts: utc_midnight(2017, month, 1) })
+            .chain((1..8).map(|month| TestSnapshot { ts: utc_midnight(2018, month, 1) }))
+            .collect();
+        let now = utc_midnight(2018, 7, 10);
+        let generation = Generation {
+            unit: Unit::Month,
+            count: 18,
+            mode: IntervalMode::Calendar,
+            timezone: Tz::UTC,
+        };
+        let expected: Vec<TestSnapshot> = (1..13)
+            .map(|month| TestSnapshot { ts: utc_midnight(2017, month, 1) })
+            .chain((1..7).map(|month| TestSnapshot { ts: utc_midnight(2018, month, 1) }))
+            .collect();
+
+        let filtered = filter_by_generation(&snapshots, &generation, &now);
+        assert_eq!(filtered, expected);
+
+        let fixed_generation = Generation {
+            mode: IntervalMode::Fixed,
+            ..generation
+        };
+        let fixed_filtered = filter_by_generation(&snapshots, &fixed_generation, &now);
+        assert!(fixed_filtered.len() < expected.len());
+    }
+
+    #[test]
+    fn parse_strategy() {
+        assert_eq!("nearest".parse(), Ok(Strategy::Nearest));
+        assert_eq!("bucket-oldest".parse(), Ok(Strategy::BucketOldest));
+        assert_eq!("bucket-newest".parse(), Ok(Strategy::BucketNewest));
+        assert!("closest".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn bucket_daily() {
+        let snapshots = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(0, 0, 0) },
+        ];
+
+        let oldest = filter_by_bucket(&snapshots, &day_generation(3), true);
+        assert_eq!(oldest, vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(0, 0, 0) },
+        ]);
+
+        let newest = filter_by_bucket(&snapshots, &day_generation(3), false);
+        assert_eq!(newest, vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(12, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(0, 0, 0) },
+        ]);
+    }
+
+    #[test]
+    fn bucket_iso_weeks() {
+        // 2018-12-31 is a Monday and belongs to the first ISO week of 2019
+        let snapshots = vec![
+            TestSnapshot { ts: utc_midnight(2018, 12, 23) },
+            TestSnapshot { ts: utc_midnight(2018, 12, 24) },
+            TestSnapshot { ts: utc_midnight(2018, 12, 30) },
+            TestSnapshot { ts: utc_midnight(2018, 12, 31) },
+            TestSnapshot { ts: utc_midnight(2019, 1, 6) },
+        ];
+        let generation = Generation {
+            unit: Unit::Week,
+            count: 10,
+            mode: IntervalMode::Calendar,
+            timezone: Tz::UTC,
+        };
+        let expected = vec![
+            TestSnapshot { ts: utc_midnight(2018, 12, 23) },
+            TestSnapshot { ts: utc_midnight(2018, 12, 24) },
+            TestSnapshot { ts: utc_midnight(2018, 12, 31) },
+        ];
+
+        let filtered = filter_by_bucket(&snapshots, &generation, true);
+        assert_eq!(filtered, expected);
+    }
+
+    #[test]
+    fn daily_targets_across_dst() {
+        // Daylight saving time starts in Helsinki on 2018-03-25 at 03:00
+        // EET, when the clocks jump to 04:00 EEST
+        let generation = Generation {
+            timezone: Tz::Europe__Helsinki,
+            ..day_generation(2)
+        };
+        let now = Utc.ymd(2018, 3, 26).and_hms(9, 0, 0);
+
+        assert_eq!(generation.target(&now, 1), Some(Utc.ymd(2018, 3, 25).and_hms(9, 0, 0)));
+        assert_eq!(generation.target(&now, 2), Some(Utc.ymd(2018, 3, 24).and_hms(10, 0, 0)));
+
+        // 03:30 doesn't exist on 2018-03-25
+        let now = Utc.ymd(2018, 3, 26).and_hms(0, 30, 0);
+        assert_eq!(generation.target(&now, 1), Some(Utc.ymd(2018, 3, 25).and_hms(1, 30, 0)));
+
+        // 03:30 happens twice on 2018-10-28, first in EEST and then in EET
+        let now = Utc.ymd(2018, 10, 29).and_hms(1, 30, 0);
+        assert_eq!(generation.target(&now, 1), Some(Utc.ymd(2018, 10, 28).and_hms(0, 30, 0)));
+    }
+
+    #[test]
+    fn bucket_daily_in_timezone() {
+        // Brisbane is UTC+10 all year
+        let snapshots = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(20, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(2, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(16, 0, 0) },
+        ];
+        let generation = Generation {
+            timezone: Tz::Australia__Brisbane,
+            ..day_generation(3)
+        };
+
+        assert_eq!(filter_by_bucket(&snapshots, &generation, true), vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(20, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(16, 0, 0) },
+        ]);
+        assert_eq!(filter_by_bucket(&snapshots, &day_generation(3), true), vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(20, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(2, 0, 0) },
+        ]);
+    }
+
+    #[test]
+    fn bucket_hourly_across_dst_end() {
+        // The hour from 03:00 to 04:00 is repeated in Helsinki on
+        // 2018-10-28
+        let first = Utc.ymd(2018, 10, 28).and_hms(0, 0, 0);
+        let snapshots: Vec<TestSnapshot> = (0..4)
+            .map(|i| TestSnapshot { ts: first + Duration::minutes(30 * i) })
+            .collect();
+        let generation = Generation {
+            timezone: Tz::Europe__Helsinki,
+            ..hour_generation(2)
+        };
+
+        assert_eq!(filter_by_bucket(&snapshots, &generation, true), vec![
+            TestSnapshot { ts: Utc.ymd(2018, 10, 28).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 10, 28).and_hms(1, 0, 0) },
+        ]);
+
+        // India is UTC+5:30, so the local hours start at half past in UTC
+        let generation = Generation {
+            timezone: Tz::Asia__Kolkata,
+            ..hour_generation(2)
+        };
+        assert_eq!(filter_by_bucket(&snapshots, &generation, true), vec![
+            TestSnapshot { ts: Utc.ymd(2018, 10, 28).and_hms(0, 30, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 10, 28).and_hms(1, 30, 0) },
+        ]);
+    }
+
+    #[test]
+    fn bucket_zero_count() {
+        let snapshots = vec![TestSnapshot { ts: utc_midnight(2018, 3, 1) }];
+        let filtered = filter_by_bucket(&snapshots, &month_generation(0), true);
+        assert_eq!(filtered, Vec::new());
+    }
+
+    #[test]
+    fn bucket_decisions_are_stable() {
+        // Daily snapshots for half a year
+        let first = utc_midnight(2018, 1, 1);
+        let snapshots: Vec<Snapshot> = (0..180)
+            .map(|i| Snapshot {
+                name: format!("day_{}", i),
+                ts: first + Duration::days(i),
+            })
+            .collect();
+        let generations = vec![day_generation(6), month_generation(4)];
+
+        // A snapshot that is deleted by one run is never wanted by a
+        // later run
+        let mut deleted: HashSet<String> = HashSet::new();
+        for day in 30..180 {
+            let now = first + Duration::days(day) + Duration::hours(1);
+            let available: Vec<Snapshot> = snapshots
+                .iter()
+                .filter(|x| x.ts <= now && !deleted.contains(&x.name))
+                .cloned()
+                .collect();
+            let keep = keep_generations(&available, &generations, Strategy::BucketOldest, &now);
+            for x in &available {
+                if !keep.contains(&x.name) {
+                    deleted.insert(x.name.clone());
+                }
+            }
+
+            // Deleting earlier doesn't change the decision
+            let all_until_now: Vec<Snapshot> = snapshots
+                .iter()
+                .filter(|x| x.ts <= now)
+                .cloned()
+                .collect();
+            let keep_all =
+                keep_generations(&all_until_now, &generations, Strategy::BucketOldest, &now);
+            assert_eq!(keep_all, keep);
+        }
+
+        // The first snapshot of each of the last four months survives
+        assert!(deleted.contains("day_31"));
+        assert!(!deleted.contains("day_59"));
+        assert!(!deleted.contains("day_90"));
+        assert!(!deleted.contains("day_120"));
+        assert!(!deleted.contains("day_151"));
+    }
+
+    #[test]
+    fn generation_display() {
+        let args = vec!["48H".to_string(), "31D".to_string(), "1Y".to_string()];
+        let formatted: Vec<String> =
+            parse_generations(args.clone(), IntervalMode::Calendar, Tz::UTC)
+                .unwrap()
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(formatted, args);
+    }
+
+    #[test]
+    fn reasons_list_all_slots() {
+        let snapshots = vec![
+            Snapshot {
+                name: "jun_1".to_string(),
+                ts: utc_midnight(2018, 6, 1),
+            },
+            Snapshot {
+                name: "jul_1".to_string(),
+                ts: utc_midnight(2018, 7, 1),
+            },
+            Snapshot {
+                name: "jul_2".to_string(),
+                ts: utc_midnight(2018, 7, 2),
+            },
+        ];
+        let generations = vec![day_generation(2), month_generation(2)];
+        let now = Utc.ymd(2018, 7, 2).and_hms(6, 0, 0);
+
+        let reasons = keep_reasons(&snapshots, &generations, Strategy::Nearest, &now);
+        let generation_reason = |generation: &str, slot: usize| KeepReason::Generation {
+            generation: generation.to_string(),
+            slot,
+        };
+
+        assert_eq!(reasons.len(), 3);
+        assert_eq!(
+            reasons["jul_1"],
+            vec![generation_reason("2D", 1), generation_reason("2D", 2)]
+        );
+        assert_eq!(
+            reasons["jun_1"],
+            vec![generation_reason("2M", 1), generation_reason("2M", 2)]
+        );
+        assert_eq!(reasons["jul_2"], vec![KeepReason::Latest]);
+    }
+
+    #[test]
+    fn bucket_slots_are_numbered_from_latest() {
+        let snapshots = vec![
+            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(0, 0, 0) },
+            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
+        ];
+        let now = Utc.ymd(2018, 7, 14).and_hms(12, 0, 0);
+
+        let slots = fill_slots(&snapshots, &day_generation(3), Strategy::BucketOldest, &now);
+        assert_eq!(
+            slots,
+            vec![
+                Slot {
+                    index: 1,
+                    target: None,
+                    snapshot: TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
+                },
+                Slot {
+                    index: 2,
+                    target: None,
+                    snapshot: TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(0, 0, 0) },
+                },
+            ]
+        );
+    }
+}
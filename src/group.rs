@@ -0,0 +1,447 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use regex::{self, Regex};
+use chrono::prelude::*;
+use chrono::Duration;
+use generation::{Generation, KeepReason, Strategy, keep_reasons};
+use hold::Holds;
+use snapshot::Snapshot;
+
+/// A set of archives, selected by a name pattern, that is rotated
+/// independently of other archives.
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub name: String,
+    pub pattern: Regex,
+    pub generations: Vec<Generation>,
+}
+
+impl Group {
+    /// A group that contains all archives.
+    pub fn all(generations: Vec<Generation>) -> Group {
+        Group {
+            name: "all".to_string(),
+            pattern: Regex::new("").unwrap(),
+            generations,
+        }
+    }
+
+    pub fn matches(&self, snapshot: &Snapshot) -> bool {
+        self.pattern.is_match(&snapshot.name)
+    }
+}
+
+/// Convert a shell glob into an anchored regular expression. `*` matches
+/// any number of characters and `?` matches one character. All other
+/// characters match themselves.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = "^".to_string();
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            _ => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Return the index of the group the snapshot belongs to, that is the
+/// first group whose pattern matches its name.
+pub fn group_index(groups: &[Group], snapshot: &Snapshot) -> Option<usize> {
+    groups.iter().position(|g| g.matches(snapshot))
+}
+
+/// Rules that keep snapshots regardless of the generations. `keep_last`
+/// applies to each group separately.
+#[derive(Clone, Debug, Default)]
+pub struct Protection {
+    /// Always keep this many of the most recent snapshots.
+    pub keep_last: usize,
+    /// Never delete snapshots younger than this.
+    pub min_age: Option<Duration>,
+    /// Never delete snapshots whose name matches one of these.
+    pub patterns: Vec<Regex>,
+    /// Never delete these snapshots.
+    pub pinned: Vec<String>,
+    /// Don't delete these snapshots until the holds expire.
+    pub holds: Holds,
+}
+
+impl Protection {
+    // Add the protection reasons to the reasons of the members of a group
+    fn protect(
+        &self,
+        members: &[Snapshot],
+        now: &DateTime<Utc>,
+        reasons: &mut HashMap<String, Vec<KeepReason>>,
+    ) {
+        let mut newest_first: Vec<&Snapshot> = members.iter().collect();
+        newest_first.sort_by_key(|x| Reverse(x.ts));
+        for (i, snapshot) in newest_first.iter().take(self.keep_last).enumerate() {
+            reasons
+                .entry(snapshot.name.clone())
+                .or_default()
+                .push(KeepReason::KeepLast { position: i + 1 });
+        }
+
+        for snapshot in members {
+            let mut protected = Vec::new();
+            if self.min_age.is_some_and(|min_age| *now - snapshot.ts < min_age) {
+                protected.push(KeepReason::MinAge);
+            }
+            for pattern in self.patterns.iter().filter(|re| re.is_match(&snapshot.name)) {
+                protected.push(KeepReason::Protected {
+                    pattern: pattern.as_str().to_string(),
+                });
+            }
+            if self.pinned.contains(&snapshot.name) {
+                protected.push(KeepReason::Pinned);
+            }
+            for hold in self.holds.holds.iter().filter(|x| x.archive == snapshot.name) {
+                if !hold.expired(now) {
+                    protected.push(KeepReason::Hold {
+                        reason: hold.reason.clone(),
+                        expires: hold.expires,
+                    });
+                }
+            }
+
+            if !protected.is_empty() {
+                reasons
+                    .entry(snapshot.name.clone())
+                    .or_default()
+                    .extend(protected);
+            }
+        }
+    }
+}
+
+/// The retention decision for one snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decision {
+    pub snapshot: Snapshot,
+    /// The name of the group the snapshot belongs to.
+    pub group: Option<String>,
+    /// Why the snapshot is kept. The snapshot is deleted if this is empty.
+    pub reasons: Vec<KeepReason>,
+}
+
+impl Decision {
+    pub fn keep(&self) -> bool {
+        !self.reasons.is_empty()
+    }
+}
+
+/// Return the names of the snapshots that are not needed by the
+/// generations of their group and not kept by `protection`.
+///
+/// Each group is rotated on its own and always keeps its latest snapshot.
+/// A snapshot belongs to the first group whose pattern matches its name.
+/// Snapshots that don't belong to any group are never deleted.
+pub fn select_group_snapshots_to_delete(
+    groups: &[Group],
+    strategy: Strategy,
+    protection: &Protection,
+    now: &DateTime<Utc>,
+    snapshots: Vec<Snapshot>,
+) -> Vec<String> {
+    decide(groups, strategy, protection, now, snapshots)
+        .into_iter()
+        .filter(|x| !x.keep())
+        .map(|x| x.snapshot.name)
+        .collect()
+}
+
+/// Decide which snapshots are kept and why. The decisions are in the same
+/// order as `snapshots`.
+pub fn decide(
+    groups: &[Group],
+    strategy: Strategy,
+    protection: &Protection,
+    now: &DateTime<Utc>,
+    snapshots: Vec<Snapshot>,
+) -> Vec<Decision> {
+    let membership: Vec<Option<usize>> = snapshots
+        .iter()
+        .map(|snapshot| group_index(groups, snapshot))
+        .collect();
+
+    let mut group_reasons = Vec::new();
+    for (i, group) in groups.iter().enumerate() {
+        let members: Vec<Snapshot> = snapshots
+            .iter()
+            .zip(&membership)
+            .filter(|&(_, m)| *m == Some(i))
+            .map(|(x, _)| x.clone())
+            .collect();
+        debug!("Group {} has {} snapshots", group.name, members.len());
+        let mut reasons = keep_reasons(&members, &group.generations, strategy, now);
+        protection.protect(&members, now, &mut reasons);
+        group_reasons.push(reasons);
+    }
+
+    snapshots
+        .into_iter()
+        .zip(membership)
+        .map(|(snapshot, m)| match m {
+            Some(i) => Decision {
+                group: Some(groups[i].name.clone()),
+                reasons: group_reasons[i].remove(&snapshot.name).unwrap_or_default(),
+                snapshot,
+            },
+            None => {
+                debug!("{} doesn't belong to any group, keeping it", snapshot.name);
+                Decision {
+                    group: None,
+                    reasons: vec![KeepReason::Ungrouped],
+                    snapshot,
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+    use generation::{IntervalMode, Unit};
+    use hold::Hold;
+
+    fn day_generation(n: usize) -> Generation {
+        Generation {
+            unit: Unit::Day,
+            count: n,
+            mode: IntervalMode::Calendar,
+            timezone: Tz::UTC,
+        }
+    }
+
+    fn snapshot(name: &str, day: u32) -> Snapshot {
+        Snapshot {
+            name: name.to_string(),
+            ts: Utc.ymd(2018, 7, day).and_hms(1, 0, 0),
+        }
+    }
+
+    fn glob_group(glob: &str, generations: Vec<Generation>) -> Group {
+        Group {
+            name: glob.to_string(),
+            pattern: Regex::new(&glob_to_regex(glob)).unwrap(),
+            generations,
+        }
+    }
+
+    #[test]
+    fn glob_conversion() {
+        let re = Regex::new(&glob_to_regex("db-?.*")).unwrap();
+        assert!(re.is_match("db-1.2018"));
+        assert!(!re.is_match("db-12.2018"));
+        assert!(!re.is_match("db-1-2018"));
+        assert!(!re.is_match("xdb-1.2018"));
+    }
+
+    #[test]
+    fn groups_are_rotated_separately() {
+        let snapshots = vec![
+            snapshot("etc-10", 10),
+            snapshot("etc-11", 11),
+            snapshot("etc-12", 12),
+            snapshot("db-08", 8),
+            snapshot("db-09", 9),
+            snapshot("home-01", 1),
+        ];
+        let groups = vec![
+            glob_group("etc-*", vec![day_generation(1)]),
+            glob_group("db-*", vec![day_generation(1)]),
+        ];
+        let now = Utc.ymd(2018, 7, 12).and_hms(12, 0, 0);
+
+        let mut deleted = select_group_snapshots_to_delete(
+            &groups,
+            Strategy::Nearest,
+            &Protection::default(),
+            &now,
+            snapshots,
+        );
+        deleted.sort();
+
+        // The daily etc snapshot doesn't satisfy the daily slot of db and
+        // the ungrouped home snapshot is left alone
+        assert_eq!(deleted, vec!["db-08".to_string(), "etc-10".to_string()]);
+    }
+
+    #[test]
+    fn first_matching_group_wins() {
+        let snapshots = vec![
+            snapshot("db-01", 1),
+            snapshot("db-02", 2),
+            snapshot("db-03", 3),
+        ];
+        let groups = vec![
+            glob_group("db-*", vec![]),
+            glob_group("*", vec![day_generation(10)]),
+        ];
+        let now = Utc.ymd(2018, 7, 4).and_hms(12, 0, 0);
+
+        let mut deleted = select_group_snapshots_to_delete(
+            &groups,
+            Strategy::Nearest,
+            &Protection::default(),
+            &now,
+            snapshots,
+        );
+        deleted.sort();
+
+        assert_eq!(deleted, vec!["db-01".to_string(), "db-02".to_string()]);
+    }
+
+    #[test]
+    fn decisions_keep_input_order() {
+        let snapshots = vec![
+            snapshot("etc-12", 12),
+            snapshot("home-01", 1),
+            snapshot("etc-11", 11),
+            snapshot("etc-10", 10),
+        ];
+        let groups = vec![glob_group("etc-*", vec![day_generation(1)])];
+        let now = Utc.ymd(2018, 7, 12).and_hms(12, 0, 0);
+
+        let decisions = decide(&groups, Strategy::Nearest, &Protection::default(), &now, snapshots);
+        let names: Vec<&str> = decisions.iter().map(|x| x.snapshot.name.as_str()).collect();
+        let reasons: Vec<Vec<KeepReason>> = decisions.iter().map(|x| x.reasons.clone()).collect();
+
+        assert_eq!(names, vec!["etc-12", "home-01", "etc-11", "etc-10"]);
+        assert_eq!(
+            reasons,
+            vec![
+                vec![KeepReason::Latest],
+                vec![KeepReason::Ungrouped],
+                vec![KeepReason::Generation { generation: "1D".to_string(), slot: 1 }],
+                vec![],
+            ]
+        );
+        assert_eq!(decisions[0].group, Some("etc-*".to_string()));
+        assert_eq!(decisions[1].group, None);
+    }
+
+    #[test]
+    fn protection_keeps_recent_snapshots() {
+        let snapshots = vec![
+            snapshot("db-01", 1),
+            snapshot("db-08", 8),
+            snapshot("db-09", 9),
+            snapshot("db-10", 10),
+            snapshot("db-11", 11),
+        ];
+        let groups = vec![glob_group("db-*", vec![])];
+        let protection = Protection {
+            keep_last: 2,
+            min_age: Some(Duration::hours(60)),
+            ..Protection::default()
+        };
+        let now = Utc.ymd(2018, 7, 11).and_hms(12, 0, 0);
+
+        let decisions = decide(&groups, Strategy::Nearest, &protection, &now, snapshots);
+        let reasons: Vec<Vec<KeepReason>> = decisions.iter().map(|x| x.reasons.clone()).collect();
+
+        assert_eq!(
+            reasons,
+            vec![
+                vec![],
+                vec![],
+                vec![KeepReason::MinAge],
+                vec![KeepReason::KeepLast { position: 2 }, KeepReason::MinAge],
+                vec![
+                    KeepReason::Latest,
+                    KeepReason::KeepLast { position: 1 },
+                    KeepReason::MinAge,
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn pinned_snapshots_are_kept() {
+        let snapshots = vec![
+            snapshot("release-1", 1),
+            snapshot("db-02", 2),
+            snapshot("db-03", 3),
+            snapshot("db-04", 4),
+            snapshot("db-05", 5),
+        ];
+        let groups = vec![glob_group("*", vec![])];
+        let hold = |archive: &str, expires_day: u32| Hold {
+            archive: archive.to_string(),
+            reason: Some("audit".to_string()),
+            expires: Some(Utc.ymd(2018, 7, expires_day).and_hms(0, 0, 0)),
+            created: Utc.ymd(2018, 7, 1).and_hms(0, 0, 0),
+        };
+        let protection = Protection {
+            patterns: vec![Regex::new("^release-").unwrap()],
+            pinned: vec!["db-02".to_string()],
+            holds: Holds {
+                holds: vec![hold("db-03", 31), hold("db-04", 5)],
+            },
+            ..Protection::default()
+        };
+        let now = Utc.ymd(2018, 7, 10).and_hms(12, 0, 0);
+
+        let deleted = select_group_snapshots_to_delete(
+            &groups,
+            Strategy::Nearest,
+            &protection,
+            &now,
+            snapshots.clone(),
+        );
+        assert_eq!(deleted, vec!["db-04".to_string()]);
+
+        let decisions = decide(&groups, Strategy::Nearest, &protection, &now, snapshots);
+        let reasons: Vec<Vec<KeepReason>> = decisions.iter().map(|x| x.reasons.clone()).collect();
+
+        assert_eq!(
+            reasons,
+            vec![
+                vec![KeepReason::Protected { pattern: "^release-".to_string() }],
+                vec![KeepReason::Pinned],
+                vec![
+                    KeepReason::Hold {
+                        reason: Some("audit".to_string()),
+                        expires: Some(Utc.ymd(2018, 7, 31).and_hms(0, 0, 0)),
+                    },
+                ],
+                // The hold has expired
+                vec![],
+                vec![KeepReason::Latest],
+            ]
+        );
+    }
+}
@@ -0,0 +1,225 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use chrono::prelude::*;
+use chrono_tz::Tz;
+use toml;
+use error::Error;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// A hold keeps an archive until it expires, or forever if it has no
+/// expiry time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hold {
+    pub archive: String,
+    pub reason: Option<String>,
+    pub expires: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+}
+
+impl Hold {
+    pub fn expired(&self, now: &DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|t| t <= *now)
+    }
+}
+
+/// The local holds database. It is stored as a TOML file with one
+/// `[[hold]]` table per hold.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Holds {
+    #[serde(default, rename = "hold")]
+    pub holds: Vec<Hold>,
+}
+
+impl Holds {
+    /// Add a hold. It replaces an earlier hold on the same archive.
+    pub fn add(&mut self, hold: Hold) {
+        self.remove(&hold.archive);
+        self.holds.push(hold);
+    }
+
+    /// Remove the hold on an archive. Returns false if there was no hold.
+    pub fn remove(&mut self, archive: &str) -> bool {
+        let len = self.holds.len();
+        self.holds.retain(|x| x.archive != archive);
+        self.holds.len() != len
+    }
+
+    /// The holds that have expired by `now`.
+    pub fn expired(&self, now: &DateTime<Utc>) -> Vec<&Hold> {
+        self.holds.iter().filter(|x| x.expired(now)).collect()
+    }
+}
+
+/// Format the holds for `hold list`, one per line. The expiry times are
+/// shown in `timezone`.
+pub fn format_holds(holds: &Holds, now: &DateTime<Utc>, timezone: &Tz) -> String {
+    let local = |t: &DateTime<Utc>| t.with_timezone(timezone).format(TIME_FORMAT);
+    holds
+        .holds
+        .iter()
+        .map(|hold| {
+            let expiry = match hold.expires {
+                Some(t) if hold.expired(now) => format!("expired {}", local(&t)),
+                Some(t) => format!("expires {}", local(&t)),
+                None => "never expires".to_string(),
+            };
+            match hold.reason {
+                Some(ref reason) => format!("{}\t{}\t{}\n", hold.archive, expiry, reason),
+                None => format!("{}\t{}\n", hold.archive, expiry),
+            }
+        })
+        .collect()
+}
+
+/// Read the holds database. The file must exist; `hold add` creates it.
+pub fn load_holds<P: AsRef<Path>>(path: P) -> Result<Holds, Error> {
+    let path = path.as_ref();
+    let config_error = |line: Option<usize>, message: String| Error::Config {
+        path: Some(path.to_path_buf()),
+        line,
+        message,
+    };
+
+    match fs::read_to_string(path) {
+        Ok(source) => toml::from_str(&source).map_err(|err| {
+            config_error(err.line_col().map(|(line, _)| line + 1), err.to_string())
+        }),
+        Err(err) => Err(read_error(path, &err)),
+    }
+}
+
+/// Write the holds database. The file is replaced atomically.
+pub fn save_holds<P: AsRef<Path>>(path: P, holds: &Holds) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let contents = toml::to_string(holds).map_err(|err| Error::io(path, err))?;
+    fs::write(&tmp, contents)
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|err| Error::io(path, err))
+}
+
+/// Read the archive names, one per line, from a file of protected
+/// archives. Empty lines and lines starting with # are ignored.
+pub fn load_protect_file<P: AsRef<Path>>(path: P) -> Result<Vec<String>, Error> {
+    let path = path.as_ref();
+    fs::read_to_string(path)
+        .map_err(|err| read_error(path, &err))
+        .map(|contents| {
+            contents
+                .lines()
+                .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string())
+                .collect()
+        })
+}
+
+// A missing file is a configuration error, because the configuration
+// names it. Other failures to read it are I/O errors.
+fn read_error(path: &Path, err: &io::Error) -> Error {
+    if err.kind() == io::ErrorKind::NotFound {
+        Error::Config {
+            path: Some(path.to_path_buf()),
+            line: None,
+            message: err.to_string(),
+        }
+    } else {
+        Error::io(path, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn hold(archive: &str, expires: Option<DateTime<Utc>>) -> Hold {
+        Hold {
+            archive: archive.to_string(),
+            reason: Some("incident 42".to_string()),
+            expires,
+            created: Utc.ymd(2018, 7, 1).and_hms(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn add_and_remove() {
+        let mut holds = Holds::default();
+        holds.add(hold("a", None));
+        holds.add(hold("b", None));
+        holds.add(hold("a", Some(Utc.ymd(2019, 1, 1).and_hms(0, 0, 0))));
+
+        assert_eq!(holds.holds.len(), 2);
+        assert_eq!(holds.holds[1].expires, Some(Utc.ymd(2019, 1, 1).and_hms(0, 0, 0)));
+        assert!(holds.remove("a"));
+        assert!(!holds.remove("a"));
+        assert_eq!(holds.holds, vec![hold("b", None)]);
+    }
+
+    #[test]
+    fn expiry() {
+        let mut holds = Holds::default();
+        holds.add(hold("forever", None));
+        holds.add(hold("expired", Some(Utc.ymd(2018, 7, 10).and_hms(0, 0, 0))));
+        holds.add(hold("active", Some(Utc.ymd(2018, 8, 1).and_hms(0, 0, 0))));
+        let now = Utc.ymd(2018, 7, 20).and_hms(0, 0, 0);
+
+        let expired: Vec<&str> = holds.expired(&now).iter().map(|x| x.archive.as_str()).collect();
+        assert_eq!(expired, vec!["expired"]);
+        assert_eq!(
+            format_holds(&holds, &now, &Tz::UTC),
+            "forever\tnever expires\tincident 42\n\
+             expired\texpired 2018-07-10 00:00:00\tincident 42\n\
+             active\texpires 2018-08-01 00:00:00\tincident 42\n"
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("holds.toml");
+        match load_holds(&path) {
+            Err(Error::Config { path: Some(ref p), line: None, .. }) => assert_eq!(p, &path),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut holds = Holds::default();
+        holds.add(hold("a", Some(Utc.ymd(2019, 1, 1).and_hms(0, 0, 0))));
+        holds.add(Hold {
+            reason: None,
+            ..hold("b", None)
+        });
+        save_holds(&path, &holds).unwrap();
+
+        assert_eq!(load_holds(&path), Ok(holds));
+    }
+}
@@ -0,0 +1,131 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+//! Expire tarsnap backups following the grandfather-father-son backup
+//! rotation scheme.
+//!
+//! The retention logic works on anything that implements
+//! [`SnapshotTimestamp`](trait.SnapshotTimestamp.html). The command line
+//! tool uses [`Snapshot`](struct.Snapshot.html), which are parsed from the
+//! `tarsnap --list-archives -v` output with
+//! [`parse_archives`](fn.parse_archives.html).
+//!
+//! The archives are listed and deleted, and their storage sizes are read,
+//! through the [`ArchiveBackend`](trait.ArchiveBackend.html) trait.
+//! [`Tarsnap`](struct.Tarsnap.html) runs the tarsnap command line client
+//! and [`MemoryBackend`](struct.MemoryBackend.html) keeps the archives in
+//! memory.
+//!
+//! ```
+//! extern crate chrono;
+//! extern crate tarsnap_lifespan;
+//!
+//! use chrono::prelude::*;
+//! use tarsnap_lifespan::{parse_generations, select_snapshots_to_delete, IntervalMode,
+//!                        Protection, Snapshot, Strategy, Tz};
+//!
+//! # fn main() {
+//! let generations =
+//!     parse_generations(vec!["2D".to_string()], IntervalMode::Calendar, Tz::UTC).unwrap();
+//! let snapshots = vec![
+//!     Snapshot { name: "a".to_string(), ts: Utc.ymd(2018, 7, 10).and_hms(0, 0, 0) },
+//!     Snapshot { name: "b".to_string(), ts: Utc.ymd(2018, 7, 12).and_hms(0, 0, 0) },
+//!     Snapshot { name: "c".to_string(), ts: Utc.ymd(2018, 7, 13).and_hms(0, 0, 0) },
+//!     Snapshot { name: "d".to_string(), ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
+//! ];
+//! let now = Utc.ymd(2018, 7, 14).and_hms(1, 0, 0);
+//!
+//! let protection = Protection { pinned: vec!["b".to_string()], ..Protection::default() };
+//! let expired =
+//!     select_snapshots_to_delete(&generations, Strategy::Nearest, &protection, &now, snapshots);
+//! assert_eq!(expired, vec!["a".to_string()]);
+//! # }
+//! ```
+
+extern crate regex;
+extern crate chrono;
+extern crate chrono_tz;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+extern crate fs2;
+extern crate libc;
+extern crate hostname;
+#[macro_use]
+extern crate log;
+#[cfg(test)]
+#[macro_use]
+extern crate indoc;
+#[cfg(test)]
+extern crate tempfile;
+
+mod audit;
+mod backend;
+mod config;
+mod duration;
+mod error;
+mod explain;
+mod generation;
+mod group;
+mod hold;
+mod limit;
+mod lock;
+mod metrics;
+mod report;
+mod simulate;
+mod snapshot;
+mod stats;
+mod tarsnap;
+mod timestamp;
+
+pub use audit::{AuditLog, describe_policy};
+pub use backend::{ArchiveBackend, ArchiveDeletion, ArchiveStatus, Deletion, MemoryBackend,
+                  delete_in_batches, delete_snapshots};
+pub use config::{Config, GroupConfig, PolicyConfig, load_config, parse_config};
+pub use duration::{format_duration, parse_duration};
+pub use error::Error;
+pub use explain::explain;
+pub use generation::{Generation, IntervalMode, KeepReason, Slot, Strategy, Unit,
+                     parse_generations, select_snapshots_to_delete, keep_generations,
+                     keep_reasons, fill_slots, filter_by_generation, filter_by_bucket};
+pub use group::{Decision, Group, Protection, decide, glob_to_regex,
+                select_group_snapshots_to_delete};
+pub use hold::{Hold, Holds, format_holds, load_holds, load_protect_file, save_holds};
+pub use limit::{ClockCheck, DeleteLimit, parse_percent};
+pub use lock::{Lock, default_lock_path};
+pub use metrics::{Metrics, SlotMetric, write_metrics};
+pub use report::{Action, DeletionResult, Format, Report, SnapshotReport, Summary};
+pub use simulate::{CronSchedule, SimulationStep, format_simulation, scheduled_snapshots,
+                   simulate};
+pub use snapshot::{Snapshot, SnapshotTimestamp, check_archive_names, parse_archives,
+                   parse_null_archives};
+pub use stats::{StorageReport, StorageSizes, StorageStats, format_storage, parse_stats};
+pub use tarsnap::Tarsnap;
+pub use timestamp::{NameTimestamp, Timestamp, TimestampFallback, apply_name_timestamps,
+                    from_local, parse_timestamp, parse_timezone};
+pub use chrono_tz::Tz;
@@ -0,0 +1,198 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use chrono::prelude::*;
+use chrono::Duration;
+use duration::format_duration;
+use error::Error;
+use snapshot::SnapshotTimestamp;
+
+/// Upper limits on how many archives a single run may delete. They guard
+/// against a wrong clock or a mistyped generation argument wiping out
+/// most of the archives.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DeleteLimit {
+    /// The maximum number of archives to delete.
+    pub max_count: Option<usize>,
+    /// The maximum percentage (0 - 100) of the archives to delete.
+    pub max_percent: Option<f64>,
+}
+
+impl DeleteLimit {
+    /// Fail if deleting `count` out of `total` archives exceeds the limits.
+    pub fn check(&self, count: usize, total: usize) -> Result<(), Error> {
+        let exceeded = |limit: String| {
+            Err(Error::DeleteLimit {
+                count,
+                total,
+                limit,
+            })
+        };
+
+        if let Some(max_count) = self.max_count {
+            if count > max_count {
+                return exceeded(format!("{} archives", max_count));
+            }
+        }
+        if let Some(max_percent) = self.max_percent {
+            if count as f64 * 100.0 > max_percent * total as f64 {
+                return exceeded(format!("{}% of the archives", max_percent));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bounds on the age of the newest archive. If the clock is wrong, all
+/// generation targets land far from the real archives and the wrong
+/// archives get deleted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ClockCheck {
+    /// How far in the future the newest archive may be.
+    pub max_future: Option<Duration>,
+    /// How old the newest archive may be.
+    pub max_newest_age: Option<Duration>,
+}
+
+impl ClockCheck {
+    /// Fail if the newest of `snapshots` is outside the bounds.
+    pub fn check<T>(&self, snapshots: &[T], now: &DateTime<Utc>) -> Result<(), Error>
+    where
+        T: SnapshotTimestamp,
+    {
+        let newest = match snapshots.iter().map(|x| x.timestamp()).max() {
+            Some(ts) => ts,
+            None => return Ok(()),
+        };
+
+        if let Some(max_future) = self.max_future {
+            if newest - *now > max_future {
+                return Err(Error::ClockSkew(format!(
+                    "the newest archive is {} in the future",
+                    format_duration(newest - *now)
+                )));
+            }
+        }
+        if let Some(max_newest_age) = self.max_newest_age {
+            if *now - newest > max_newest_age {
+                return Err(Error::ClockSkew(format!(
+                    "the newest archive is {} old",
+                    format_duration(*now - newest)
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a percentage between 0 and 100.
+pub fn parse_percent(s: &str) -> Result<f64, Error> {
+    match s.trim_end_matches('%').parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(Error::invalid_argument(s, "expected a percentage between 0 and 100")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snapshot::Snapshot;
+
+    #[test]
+    fn no_limits() {
+        assert_eq!(DeleteLimit::default().check(100, 100), Ok(()));
+    }
+
+    #[test]
+    fn count_and_percent_limits() {
+        let limit = DeleteLimit {
+            max_count: Some(5),
+            max_percent: Some(50.0),
+        };
+
+        assert_eq!(limit.check(5, 10), Ok(()));
+        assert_eq!(
+            limit.check(6, 20),
+            Err(Error::DeleteLimit {
+                count: 6,
+                total: 20,
+                limit: "5 archives".to_string(),
+            })
+        );
+        assert_eq!(
+            limit.check(3, 5),
+            Err(Error::DeleteLimit {
+                count: 3,
+                total: 5,
+                limit: "50% of the archives".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn clock_check() {
+        let now = Utc.ymd(2018, 7, 20).and_hms(0, 0, 0);
+        let check = ClockCheck {
+            max_future: Some(Duration::hours(1)),
+            max_newest_age: Some(Duration::days(2)),
+        };
+        let snapshots = |hours: &[i64]| -> Vec<Snapshot> {
+            hours
+                .iter()
+                .map(|&h| Snapshot {
+                    name: format!("backup-{}", h),
+                    ts: now + Duration::hours(h),
+                })
+                .collect()
+        };
+
+        assert_eq!(check.check(&snapshots(&[]), &now), Ok(()));
+        assert_eq!(check.check(&snapshots(&[-100, 1]), &now), Ok(()));
+        assert_eq!(check.check(&snapshots(&[-48]), &now), Ok(()));
+        assert_eq!(
+            check.check(&snapshots(&[-100, 26]), &now),
+            Err(Error::ClockSkew(
+                "the newest archive is 1d 2h 0m in the future".to_string()
+            ))
+        );
+        assert_eq!(
+            check.check(&snapshots(&[-100, -49]), &now),
+            Err(Error::ClockSkew("the newest archive is 2d 1h 0m old".to_string()))
+        );
+        assert_eq!(ClockCheck::default().check(&snapshots(&[10000]), &now), Ok(()));
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(parse_percent("12.5"), Ok(12.5));
+        assert_eq!(parse_percent("20%"), Ok(20.0));
+        assert!(parse_percent("101").is_err());
+        assert!(parse_percent("-1").is_err());
+        assert!(parse_percent("x").is_err());
+    }
+}
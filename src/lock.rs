@@ -0,0 +1,230 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+use fs2::FileExt;
+use error::Error;
+
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// An advisory lock that keeps two runs from deleting archives at the
+/// same time. The lock is an exclusive `flock` on the lock file, so it is
+/// released when the process exits, even if it crashes. The file contains
+/// the process id of the holder.
+///
+/// The lock is released when the `Lock` is dropped. The file itself is
+/// left in place, because removing it would let another process lock a
+/// different file of the same name.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Take the lock at `path`. If another process holds the lock, wait
+    /// for at most `wait`, or forever if `wait` is `None`.
+    ///
+    /// A new lock file is created readable and writable only by the owner.
+    /// A symbolic link at `path` is not followed, so that the run can't be
+    /// tricked into truncating the file it points to, and a lock file owned
+    /// by another user is refused.
+    pub fn acquire<P: AsRef<Path>>(path: P, wait: Option<Duration>) -> Result<Lock, Error> {
+        let path = path.as_ref();
+        let lock_error = |err: io::Error| Error::io(path, err);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .map_err(lock_error)?;
+        // Another user could hold a lock file in a shared directory forever
+        let owner = file.metadata().map_err(lock_error)?.uid();
+        if owner != current_uid() {
+            return Err(lock_error(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("the lock file is owned by another user (uid {})", owner),
+            )));
+        }
+
+        let started = Instant::now();
+        let mut waiting = false;
+        while let Err(err) = file.try_lock_exclusive() {
+            if err.raw_os_error() != fs2::lock_contended_error().raw_os_error() {
+                return Err(lock_error(err));
+            }
+            if wait.is_some_and(|wait| started.elapsed() >= wait) {
+                return Err(Error::AlreadyRunning {
+                    path: path.to_path_buf(),
+                    pid: read_pid(&mut file),
+                });
+            }
+            if !waiting {
+                info!("Waiting for another run to release {}", path.display());
+                waiting = true;
+            }
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+        }
+
+        // Nobody holds the lock, so a process id left in the file belongs
+        // to a run that has died
+        if let Some(pid) = read_pid(&mut file) {
+            info!("Taking over the stale lock {} of process {}", path.display(), pid);
+        }
+        file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| writeln!(file, "{}", process::id()))
+            .and_then(|_| file.flush())
+            .map_err(lock_error)?;
+        debug!("Locked {}", path.display());
+
+        Ok(Lock {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // Clear the process id so that the next run doesn't mistake a
+        // cleanly released lock for a stale one
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+/// The default lock file: next to the tarsnap cache directory, or, if the
+/// cache directory isn't known, in the per-user runtime directory
+/// `$XDG_RUNTIME_DIR`. Without a runtime directory, the lock file is in the
+/// temporary directory and has the user id in its name.
+pub fn default_lock_path(cachedir: Option<&Path>) -> PathBuf {
+    match cachedir {
+        Some(dir) => {
+            let mut path = dir.as_os_str().to_owned();
+            path.push(".lock");
+            PathBuf::from(path)
+        }
+        None => match env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir).join("tarsnap-lifespan.lock"),
+            None => env::temp_dir().join(format!("tarsnap-lifespan-{}.lock", current_uid())),
+        },
+    }
+}
+
+fn current_uid() -> u32 {
+    unsafe { libc::geteuid() }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_to_string(&mut contents))
+        .ok()
+        .and_then(|_| contents.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn second_lock_fails() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+
+        let lock = Lock::acquire(&path, Some(Duration::from_secs(0))).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+        assert_eq!(
+            Lock::acquire(&path, Some(Duration::from_millis(200))).unwrap_err(),
+            Error::AlreadyRunning {
+                path: path.clone(),
+                pid: Some(process::id()),
+            }
+        );
+
+        drop(lock);
+        assert!(Lock::acquire(&path, Some(Duration::from_secs(0))).is_ok());
+    }
+
+    #[test]
+    fn stale_lock() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        fs::write(&path, "99999999\n").unwrap();
+
+        let lock = Lock::acquire(&path, Some(Duration::from_secs(0))).unwrap();
+        assert_eq!(lock.path(), path.as_path());
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+    }
+
+    #[test]
+    fn lock_next_to_cachedir() {
+        assert_eq!(
+            default_lock_path(Some(Path::new("/var/cache/tarsnap"))),
+            PathBuf::from("/var/cache/tarsnap.lock")
+        );
+    }
+
+    #[test]
+    fn symlink_not_followed() {
+        let dir = TempDir::new().unwrap();
+        let target = dir.path().join("target");
+        let path = dir.path().join("lock");
+        fs::write(&target, "precious\n").unwrap();
+        ::std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        assert!(Lock::acquire(&path, Some(Duration::from_secs(0))).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "precious\n");
+    }
+
+    #[test]
+    fn owner_only_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("lock");
+        let _lock = Lock::acquire(&path, Some(Duration::from_secs(0))).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o077, 0);
+    }
+}
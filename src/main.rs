@@ -24,807 +24,839 @@ TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
 SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 */
 
-extern crate regex;
 extern crate chrono;
+extern crate regex;
 extern crate stderrlog;
 #[macro_use]
 extern crate log;
-#[macro_use]
+extern crate serde_json;
 extern crate structopt;
-#[macro_use]
-extern crate indoc;
+extern crate tarsnap_lifespan;
 
-use std::collections::HashSet;
-use std::error::Error;
-use std::process::Command;
-use regex::Regex;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Instant;
 use chrono::prelude::*;
-use chrono::{Duration, NaiveDateTime};
+use chrono::Duration;
 use structopt::StructOpt;
-
-const TARSNAP_BINARY: &str = "tarsnap";
-
-pub trait SnapshotTimestamp {
-    fn timestamp(&self) -> DateTime<Utc>;
-}
-
-#[derive(Clone, PartialEq, Debug)]
-struct Snapshot {
-    name: String,
-    ts: DateTime<Utc>,
-}
-
-impl SnapshotTimestamp for Snapshot {
-    fn timestamp(&self) -> DateTime<Utc> {
-        return self.ts;
-    }
-}
-
-#[derive(Debug)]
-struct Generation {
-    interval: Duration,
-    count: usize,
-}
+use structopt::clap;
+use regex::Regex;
+use tarsnap_lifespan::{ArchiveBackend, ArchiveStatus, Metrics, AuditLog, ClockCheck, CronSchedule,
+                       Decision, DeleteLimit, Error, Format, Group, GroupConfig, Hold, Holds,
+                       IntervalMode, KeepReason, Lock, NameTimestamp, PolicyConfig, Protection,
+                       Report, Snapshot, Strategy, Tz, Tarsnap, Timestamp, TimestampFallback,
+                       apply_name_timestamps, decide,
+                       default_lock_path, describe_policy, delete_in_batches, explain,
+                       format_holds, format_simulation, format_storage, load_config, load_holds,
+                       load_protect_file, parse_archives, parse_duration, parse_generations,
+                       parse_percent, parse_timestamp, parse_timezone, save_holds, write_metrics,
+                       scheduled_snapshots, simulate};
 
 #[derive(Debug, StructOpt)]
 struct Opt {
     /// Show verbose output. Use -vv for even more verbose
-    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences), raw(global = "true"))]
     verbose: usize,
     /// Don't actually delete anything. Useful together with --verbose
-    #[structopt(short = "d", long = "dry-run")]
+    #[structopt(short = "d", long = "dry-run", raw(global = "true"))]
     dry_run: bool,
-    /// Generations to keep: <number><H|D|W|M|Y> <...>
-    #[structopt(parse(from_str))]
-    generation_arg: String,
+    /// Delete the archives even if dry-run is set in the configuration file
+    #[structopt(long = "no-dry-run",
+                raw(global = "true", conflicts_with = "\"dry_run\""))]
+    no_dry_run: bool,
+    /// Append two JSON records of every run to this file: the decisions
+    /// before the deletion and the result after it
+    #[structopt(long = "audit-log", value_name = "PATH", parse(from_os_str),
+                raw(global = "true"))]
+    audit_log: Option<PathBuf>,
+    /// Write Prometheus metrics of the run to this file for the
+    /// node_exporter textfile collector
+    #[structopt(long = "metrics-file", value_name = "PATH", parse(from_os_str),
+                raw(global = "true"))]
+    metrics_file: Option<PathBuf>,
+    /// Lock file that keeps two runs from deleting at the same time.
+    /// Required if --cachedir isn't given [default: next to --cachedir]
+    #[structopt(long = "lock-file", value_name = "PATH", parse(from_os_str),
+                raw(global = "true"))]
+    lock_file: Option<PathBuf>,
+    /// How long to wait for another run to release the lock [default: forever]
+    #[structopt(long = "lock-wait", value_name = "DURATION",
+                parse(try_from_str = "parse_duration"), raw(global = "true"))]
+    lock_wait: Option<Duration>,
+    /// Exit immediately if another run holds the lock
+    #[structopt(long = "no-wait", raw(global = "true"))]
+    no_wait: bool,
+    /// Report the storage that deleting the archives frees, using
+    /// tarsnap --print-stats
+    #[structopt(long = "print-stats", raw(global = "true"))]
+    print_stats: bool,
+    /// Don't report the storage statistics
+    #[structopt(long = "no-print-stats",
+                raw(global = "true", conflicts_with = "\"print_stats\""))]
+    no_print_stats: bool,
+    /// Delete at most N archives per tarsnap call [default: all at once]
+    #[structopt(long = "batch-size", value_name = "N", raw(global = "true"))]
+    batch_size: Option<usize>,
+    /// Abort without deleting anything if more than N archives would be deleted
+    #[structopt(long = "max-delete", value_name = "N", raw(global = "true"))]
+    max_delete: Option<usize>,
+    /// Abort without deleting anything if more than P percent of the archives
+    /// would be deleted
+    #[structopt(long = "max-delete-percent", value_name = "P",
+                parse(try_from_str = "parse_percent"), raw(global = "true"))]
+    max_delete_percent: Option<f64>,
+    /// Delete even if --max-delete, --max-delete-percent, --max-future or
+    /// --max-newest-age is exceeded
+    #[structopt(long = "force", raw(global = "true"))]
+    force: bool,
+    /// Abort without deleting anything if the newest archive is more than
+    /// this far in the future [default: 1h]
+    #[structopt(long = "max-future", value_name = "DURATION",
+                parse(try_from_str = "parse_duration"), raw(global = "true"))]
+    max_future: Option<Duration>,
+    /// Abort without deleting anything if the newest archive is older than this
+    #[structopt(long = "max-newest-age", value_name = "DURATION",
+                parse(try_from_str = "parse_duration"), raw(global = "true"))]
+    max_newest_age: Option<Duration>,
+    /// Use this as the current time instead of the system clock, for
+    /// example "2018-07-20 03:00:00". The time is in the --timezone zone
+    /// unless it has an offset
+    #[structopt(long = "now", value_name = "TIMESTAMP",
+                parse(try_from_str = "parse_timestamp"), raw(global = "true"))]
+    now: Option<Timestamp>,
+    /// Always keep the N most recent archives of each group
+    #[structopt(long = "keep-last", value_name = "N", raw(global = "true"))]
+    keep_last: Option<usize>,
+    /// Never delete archives younger than this, for example 48h or 2d
+    #[structopt(long = "min-age", value_name = "DURATION",
+                parse(try_from_str = "parse_duration"), raw(global = "true"))]
+    min_age: Option<Duration>,
+    /// Never delete archives whose name matches this regex. Can be given
+    /// several times
+    #[structopt(long = "protect", value_name = "REGEX",
+                raw(number_of_values = "1", global = "true"))]
+    protect: Vec<String>,
+    /// Never delete the archives listed in this file, one name per line
+    #[structopt(long = "protect-file", value_name = "PATH", parse(from_os_str),
+                raw(global = "true"))]
+    protect_file: Option<PathBuf>,
+    /// Holds database. Archives with an active hold are never deleted
+    #[structopt(long = "holds", value_name = "PATH", parse(from_os_str), raw(global = "true"))]
+    holds: Option<PathBuf>,
+    /// Output format: text or json. json prints a report of the decisions on stdout
+    #[structopt(long = "format", default_value = "text", raw(global = "true"))]
+    format: Format,
+    /// Read the policies from this TOML configuration file
+    #[structopt(long = "config", parse(from_os_str), raw(global = "true"))]
+    config: Option<PathBuf>,
+    /// Name of the policy in the configuration file [default: default]
+    #[structopt(long = "policy", raw(global = "true"))]
+    policy: Option<String>,
+    /// How snapshots are selected: nearest, bucket-oldest or bucket-newest [default: nearest]
+    #[structopt(long = "strategy", raw(global = "true"))]
+    strategy: Option<Strategy>,
+    /// Use fixed-length months (30 days) and years (365 days) instead of calendar months and years
+    #[structopt(long = "fixed-intervals", raw(global = "true"))]
+    fixed_intervals: bool,
+    /// Use calendar months and years
+    #[structopt(long = "no-fixed-intervals",
+                raw(global = "true", conflicts_with = "\"fixed_intervals\""))]
+    no_fixed_intervals: bool,
+    /// Time zone of the day, week, month and year boundaries, of the
+    /// displayed times and of the given local times, for example
+    /// Europe/Helsinki [default: UTC]
+    #[structopt(long = "timezone", value_name = "ZONE",
+                parse(try_from_str = "parse_timezone"), raw(global = "true"))]
+    timezone: Option<Tz>,
+    /// Rotate the archives matching GLOB separately from other archives:
+    /// GLOB[=GENERATIONS], for example 'db-*=48H,31D'. Can be given several times
+    #[structopt(long = "group", raw(number_of_values = "1", global = "true"))]
+    groups: Vec<GroupConfig>,
+    /// Rotate the archives that don't belong to any group, too. By default, they are kept
+    #[structopt(long = "rotate-ungrouped", raw(global = "true"))]
+    rotate_ungrouped: bool,
+    /// Keep the archives that don't belong to any group
+    #[structopt(long = "no-rotate-ungrouped",
+                raw(global = "true", conflicts_with = "\"rotate_ungrouped\""))]
+    no_rotate_ungrouped: bool,
+    /// Take the snapshot timestamps from the archive names instead of the
+    /// creation times. FORMAT is a strftime format, such as %Y-%m-%d_%H-%M-%S,
+    /// or a regex with named groups year, month, day and optionally hour,
+    /// minute and second
+    #[structopt(long = "timestamp-from-name", value_name = "FORMAT", raw(global = "true"))]
+    timestamp_from_name: Option<NameTimestamp>,
+    /// What to do with archives whose name has no timestamp: error, skip or
+    /// creation-time [default: error]
+    #[structopt(long = "timestamp-fallback", raw(global = "true"))]
+    timestamp_fallback: Option<TimestampFallback>,
+    /// Key file for tarsnap
+    #[structopt(long = "keyfile", parse(from_os_str), raw(global = "true"))]
+    keyfile: Option<PathBuf>,
+    /// Key file for deleting archives, if different from --keyfile
+    #[structopt(long = "delete-keyfile", parse(from_os_str), raw(global = "true"))]
+    delete_keyfile: Option<PathBuf>,
+    /// Cache directory for tarsnap
+    #[structopt(long = "cachedir", parse(from_os_str), raw(global = "true"))]
+    cachedir: Option<PathBuf>,
+    /// Configuration file for tarsnap
+    #[structopt(long = "configfile", parse(from_os_str), raw(global = "true"))]
+    configfile: Option<PathBuf>,
+    /// Extra argument to pass to every tarsnap call. Can be given several times
+    #[structopt(long = "tarsnap-arg",
+                raw(number_of_values = "1", allow_hyphen_values = "true", global = "true"))]
+    tarsnap_args: Vec<String>,
+    /// Path of the tarsnap executable [default: tarsnap, looked up in PATH]
+    #[structopt(long = "tarsnap-path", parse(from_os_str), raw(global = "true"))]
+    tarsnap_path: Option<PathBuf>,
+    /// Run tarsnap through this command, for example nice or sudo. Give
+    /// each word of the command separately: --tarsnap-prefix sudo
+    /// --tarsnap-prefix -u --tarsnap-prefix backup
+    #[structopt(long = "tarsnap-prefix",
+                raw(number_of_values = "1", allow_hyphen_values = "true", global = "true"))]
+    tarsnap_prefix: Vec<String>,
+    /// List the archives without --null-output. Archive names containing
+    /// newlines can't be listed reliably without it
+    #[structopt(long = "no-null-output", raw(global = "true"))]
+    no_null_output: bool,
+    /// List the archives with --null-output even if null-output is false
+    /// in the configuration file
+    #[structopt(long = "null-output",
+                raw(global = "true", conflicts_with = "\"no_null_output\""))]
+    null_output: bool,
+    /// Fail if an archive name contains a tab, a newline or another
+    /// control character
+    #[structopt(long = "strict-names", raw(global = "true"))]
+    strict_names: bool,
+    /// Allow control characters in archive names
+    #[structopt(long = "no-strict-names",
+                raw(global = "true", conflicts_with = "\"strict_names\""))]
+    no_strict_names: bool,
+    /// Generations to keep: <number><H|D|W|M|Y> <...>. Overrides the
+    /// generations in the configuration file
     #[structopt(parse(from_str))]
-    more_generation_args: Vec<String>,
+    generation_args: Vec<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() {
-    let opt = Opt::from_args();
-
-    stderrlog::new()
-        .module(module_path!())
-        .verbosity(opt.verbose + 1)
-        .init()
-        .unwrap();
-
-    let mut generation_args = Vec::new();
-    generation_args.push(opt.generation_arg.clone());
-    generation_args.extend(opt.more_generation_args.iter().cloned());
-
-    let now = Utc::now();
-    debug!("Current time is {}", now);
-
-    let res = parse_generations(generation_args)
-        .and_then(|generations| {
-            debug!("Parsed generations: {:?}", generations);
-
-            list_archives()
-                .and_then(parse_archives)
-                .map(|snapshots| {
-                    select_snapshots_to_delete(&generations, &now, snapshots)
-                })
-                .and_then(|names| delete_snapshots(names, opt.dry_run))
-        });
-
-    if res.is_err() {
-        error!("{}", res.unwrap_err());
-        std::process::exit(1);
-    }
-}
-
-fn parse_generations(generation_args: Vec<String>) -> Result<Vec<Generation>, String> {
-    fn generation_count_from_arg(arg: &String, hours: i64) -> Result<Generation, String> {
-        Ok(Generation {
-            interval: Duration::hours(hours),
-            count: arg[..arg.len() - 1].parse::<usize>().unwrap(),
-        })
-    }
-
-    let hour_re = Regex::new(r"^(\d+)H$").unwrap();
-    let day_re = Regex::new(r"^(\d+)D$").unwrap();
-    let week_re = Regex::new(r"^(\d+)W$").unwrap();
-    let month_re = Regex::new(r"^(\d+)M$").unwrap();
-    let year_re = Regex::new(r"^(\d+)Y$").unwrap();
-
-    generation_args
-        .iter()
-        .map(|arg| if hour_re.is_match(arg) {
-            generation_count_from_arg(arg, 1)
-        } else if day_re.is_match(arg) {
-            generation_count_from_arg(arg, 24)
-        } else if week_re.is_match(arg) {
-            generation_count_from_arg(arg, 7 * 24)
-        } else if month_re.is_match(arg) {
-            generation_count_from_arg(arg, 30 * 24)
-        } else if year_re.is_match(arg) {
-            generation_count_from_arg(arg, 365 * 24)
-        } else {
-            let mut msg = "Failed to parse argument ".to_string();
-            msg.push_str(arg);
-            Err(msg)
-        })
-        .collect()
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Show which generation slots each archive fills and why it is kept.
+    /// Nothing is deleted
+    #[structopt(name = "explain")]
+    Explain {
+        /// Generations to keep: <number><H|D|W|M|Y> <...>
+        #[structopt(parse(from_str))]
+        generation_args: Vec<String>,
+    },
+    /// Replay the policy over a backup schedule or an archive listing and
+    /// show how the archives evolve. Nothing is deleted
+    #[structopt(name = "simulate")]
+    Simulate {
+        /// Backup schedule in cron format: minute hour day-of-month month
+        /// day-of-week, for example '0 3 * * *'
+        #[structopt(long = "schedule")]
+        schedule: Option<CronSchedule>,
+        /// Replay the archives in this "tarsnap --list-archives -v" output.
+        /// The schedule, if given, continues after the newest archive
+        #[structopt(long = "archives", parse(from_os_str))]
+        archives: Option<PathBuf>,
+        /// Start of the simulation [default: the oldest archive or now]
+        #[structopt(long = "start", parse(try_from_str = "parse_timestamp"))]
+        start: Option<Timestamp>,
+        /// Length of the simulation, for example 365d or 12w
+        #[structopt(long = "duration", default_value = "365d",
+                    parse(try_from_str = "parse_duration"))]
+        duration: Duration,
+        /// Time between the simulated rotation runs
+        #[structopt(long = "step", default_value = "1d", parse(try_from_str = "parse_duration"))]
+        step: Duration,
+        /// Generations to keep: <number><H|D|W|M|Y> <...>
+        #[structopt(parse(from_str))]
+        generation_args: Vec<String>,
+    },
+    /// Manage the holds that keep archives from being deleted. Requires
+    /// --holds or holds in the configuration file
+    #[structopt(name = "hold")]
+    Hold {
+        #[structopt(subcommand)]
+        command: HoldCommand,
+    },
 }
 
-fn list_archives() -> Result<String, String> {
-    Command::new(TARSNAP_BINARY)
-        .arg("--list-archives")
-        .arg("-v")
-        .env("TZ", "0")
-        .output()
-        .map_err(|err| err.to_string())
-        .and_then(|output| if output.status.success() {
-            let archives = String::from_utf8_lossy(&output.stdout).to_string();
-            debug!("Archives list:\n{}", archives);
-
-            Ok(archives)
-        } else {
-            Err(String::from_utf8_lossy(&output.stderr).to_string())
-        })
+#[derive(Debug, StructOpt)]
+enum HoldCommand {
+    /// Put a hold on an archive
+    #[structopt(name = "add")]
+    Add {
+        /// Name of the archive
+        archive: String,
+        /// The hold expires at this time, for example 2019-01-31 [default: never]
+        #[structopt(long = "expires", parse(try_from_str = "parse_timestamp"))]
+        expires: Option<Timestamp>,
+        /// Why the archive is kept
+        #[structopt(long = "reason")]
+        reason: Option<String>,
+    },
+    /// Remove the hold from an archive
+    #[structopt(name = "remove")]
+    Remove {
+        /// Name of the archive
+        archive: String,
+    },
+    /// List the holds
+    #[structopt(name = "list")]
+    List,
 }
 
-// Parse the snapshot names and creation times from the "tarsnap
-// --list-archives -v" output
-fn parse_archives(archives: String) -> Result<Vec<Snapshot>, String> {
-    archives
-        .split_terminator('\n')
-        .map(|row| parse_archive_row(row))
-        .collect()
+// The settings of a run, merged from the command line and the policy
+struct Settings<B> {
+    groups: Vec<Group>,
+    strategy: Strategy,
+    mode: IntervalMode,
+    timezone: Tz,
+    protection: Protection,
+    dry_run: bool,
+    print_stats: bool,
+    batch_size: Option<usize>,
+    audit_log: Option<PathBuf>,
+    delete_limit: DeleteLimit,
+    clock_check: ClockCheck,
+    name_timestamp: Option<NameTimestamp>,
+    timestamp_fallback: TimestampFallback,
+    backend: B,
 }
 
-// Parse one line of --list-archives -v output. For example:
-// archive-2018-07-16_11-01-03       2018-07-16 11:01:03
-fn parse_archive_row(row: &str) -> Result<Snapshot, String> {
-    let parts: Vec<&str> = row.splitn(2, '\t').collect();
-    if parts.len() == 2 {
-        parse_local_datetime_from_str(parts[1].trim()).map(|t| {
-            Snapshot {
-                name: parts[0].to_string(),
-                ts: t,
-            }
-        })
-    } else {
-        let mut msg = "Failed to parse timestamp: ".to_string();
-        msg.push_str(row);
-        Err(msg)
+impl<B: ArchiveBackend> Settings<B> {
+    // The snapshots to rotate and the ones without a timestamp in their
+    // name that are left out of the rotation
+    fn list_snapshots(&self) -> Result<(Vec<Snapshot>, Vec<Snapshot>), Error> {
+        self.backend
+            .list_archives()
+            .and_then(|snapshots| self.apply_timestamps(snapshots))
     }
-}
-
-// Parse timestamp from string such as "2018-07-14 11:15:32"
-fn parse_local_datetime_from_str(s: &str) -> Result<DateTime<Utc>, String> {
-    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
-        .map(|t| DateTime::<Utc>::from_utc(t, Utc))
-        .map_err(|err| err.description().to_string())
-}
-
-fn delete_snapshots(snapshot_names: Vec<String>, dry_run: bool) -> Result<(), String> {
-    let mut sorted_names = snapshot_names.clone();
-    sorted_names.sort_unstable();
 
-    if sorted_names.is_empty() {
-        info!("Didn't find anything to expire");
-        Ok(())
-    } else {
-        info!(
-            "snapshots selected for deletion: {}",
-            sorted_names.join(", ")
-        );
-
-        if dry_run {
-            Ok(())
-        } else {
-            let snapshot_name_args = sorted_names.iter().flat_map(|name| vec!["-f", name]);
-            Command::new(TARSNAP_BINARY)
-                .arg("-d")
-                .args(snapshot_name_args)
-                .output()
-                .map_err(|err| err.to_string())
-                .and_then(|output| if output.status.success() {
-                    Ok(())
-                } else {
-                    Err(String::from_utf8_lossy(&output.stderr).to_string())
-                })
+    fn apply_timestamps(
+        &self,
+        snapshots: Vec<Snapshot>,
+    ) -> Result<(Vec<Snapshot>, Vec<Snapshot>), Error> {
+        match self.name_timestamp {
+            Some(ref parser) => apply_name_timestamps(snapshots, parser, self.timestamp_fallback),
+            None => Ok((snapshots, Vec::new())),
         }
     }
 }
 
-fn select_snapshots_to_delete(
-    generations: &Vec<Generation>,
-    now: &DateTime<Utc>,
-    snapshots: Vec<Snapshot>,
-) -> Vec<String> {
-    let all_names: HashSet<String> = snapshots.iter().map(|x| x.name.clone()).collect();
-    let keep_names = keep_generations(&snapshots, generations, now);
-    all_names.difference(&keep_names).cloned().collect()
-}
+fn main() {
+    // Option values that don't parse are invalid arguments, like the ones
+    // that are checked later
+    let opt = match Opt::clap().get_matches_safe() {
+        Ok(matches) => Opt::from_clap(&matches),
+        Err(ref err) if err.kind == clap::ErrorKind::ValueValidation => {
+            eprintln!("{}", err.message);
+            std::process::exit(2);
+        }
+        Err(err) => err.exit(),
+    };
 
-fn keep_generations(
-    snapshots: &Vec<Snapshot>,
-    generations: &Vec<Generation>,
-    now: &DateTime<Utc>,
-) -> HashSet<String> {
-    let mut selected: HashSet<String> = generations
-        .iter()
-        .flat_map(|gen| filter_by_generation(snapshots, gen, now))
-        .map(|x| x.name)
-        .collect();
+    stderrlog::new()
+        .module(module_path!())
+        .module("tarsnap_lifespan")
+        .verbosity(opt.verbose + 1)
+        .init()
+        .unwrap();
 
-    // Always keep the latest snapshot
-    let maybe_latest = snapshots.iter().max_by_key(|x| x.timestamp()).map(|x| {
-        x.name.clone()
-    });
-    if let Some(latest) = maybe_latest {
-        selected.insert(latest);
+    if let Err(err) = run(&opt) {
+        error!("{}", err);
+        std::process::exit(err.exit_code());
     }
-
-    selected
 }
 
-fn filter_by_generation<T: SnapshotTimestamp + Clone + PartialEq>(
-    timestamps: &Vec<T>,
-    generation: &Generation,
-    now: &DateTime<Utc>,
-) -> Vec<T> {
-    let mut selected = (1..(generation.count + 1))
-        .map(|i| *now - generation.interval * (i as i32))
-        .fold(Vec::new(), |mut acc, step| {
-            let closest = timestamps.iter().min_by_key(|t| {
-                (t.timestamp().timestamp() - step.timestamp()).abs()
+fn run(opt: &Opt) -> Result<(), Error> {
+    // The configuration file is read once, so that all the settings of a
+    // run come from the same version of it
+    let policy = load_policy(opt);
+    match opt.command {
+        None => {
+            // The metrics are written even if the rest of the settings
+            // can't be loaded
+            let metrics_file = opt.metrics_file
+                .clone()
+                .or_else(|| policy.as_ref().ok().and_then(|x| x.metrics_file.clone()));
+            let started = Instant::now();
+            let mut metrics = Metrics::default();
+            // The holds and the pinned archives are read only after waiting
+            // for the lock, so that the changes made while another run held
+            // it are respected
+            let result = policy.and_then(|policy| {
+                let _lock = acquire_lock(opt, &policy)?;
+                let settings = load_settings(opt, policy, &opt.generation_args)?;
+                let now = current_time(opt, &settings.timezone);
+                rotate(settings, &now, opt.force, opt.format, &mut metrics)
             });
 
-            if let Some(x) = closest {
-                if !acc.contains(x) {
-                    acc.push(x.clone());
+            if let Some(path) = metrics_file {
+                metrics.duration = started.elapsed();
+                metrics.success = result.is_ok();
+                metrics.finished = Some(Utc::now());
+                if let Err(err) = write_metrics(&path, &metrics) {
+                    warn!("Failed to write the metrics: {}", err);
                 }
             }
 
-            acc
-        });
-
-    selected.sort_unstable_by(|a, b| a.timestamp().cmp(&b.timestamp()));
-
-    selected
-}
-
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[derive(Clone, PartialEq, Debug)]
-    struct TestSnapshot {
-        ts: DateTime<Utc>,
-    }
-
-    impl SnapshotTimestamp for TestSnapshot {
-        fn timestamp(&self) -> DateTime<Utc> {
-            return self.ts;
+            result
         }
-    }
-
-    fn test_generations() -> Vec<Generation> {
-        vec![
-            Generation {
-                interval: Duration::days(1),
-                count: 6,
-            },
-            Generation {
-                interval: Duration::days(30),
-                count: 4,
-            },
-            Generation {
-                interval: Duration::days(365),
-                count: 1,
-            },
-        ]
-    }
-
-    fn hour_generation(n: usize) -> Generation {
-        Generation {
-            interval: Duration::hours(1),
-            count: n,
+        Some(Command::Explain { ref generation_args }) => {
+            let settings = load_settings(opt, policy?, generation_args)?;
+            let now = current_time(opt, &settings.timezone);
+            let (snapshots, _) = settings.list_snapshots()?;
+            print!(
+                "{}",
+                explain(
+                    &settings.groups,
+                    settings.strategy,
+                    &settings.protection,
+                    &now,
+                    &settings.timezone,
+                    snapshots
+                )
+            );
+            Ok(())
         }
-    }
+        Some(Command::Simulate {
+            ref schedule,
+            ref archives,
+            start,
+            duration,
+            step,
+            ref generation_args,
+        }) => {
+            let settings = load_settings(opt, policy?, generation_args)?;
+            let mut backups = match *archives {
+                Some(ref path) => fs::read_to_string(path)
+                    .map_err(|err| Error::invalid_argument(path.to_string_lossy(), err))
+                    .and_then(parse_archives)
+                    .and_then(|snapshots| settings.apply_timestamps(snapshots))?
+                    .0,
+                None => Vec::new(),
+            };
+            let start = start
+                .map(|t| t.in_timezone(&settings.timezone))
+                .or_else(|| backups.iter().map(|x| x.ts).min())
+                .unwrap_or_else(|| current_time(opt, &settings.timezone));
+            let end = start.checked_add_signed(duration).ok_or_else(|| {
+                Error::invalid_argument("--duration", "the simulation would end too far away")
+            })?;
+            match (schedule, backups.iter().map(|x| x.ts).max()) {
+                (Some(schedule), Some(newest)) => backups.extend(scheduled_snapshots(
+                    schedule,
+                    &(newest + Duration::minutes(1)),
+                    &end,
+                    &settings.timezone,
+                )),
+                (Some(schedule), None) => {
+                    backups.extend(scheduled_snapshots(schedule, &start, &end, &settings.timezone))
+                }
+                (None, _) if archives.is_none() => {
+                    return Err(Error::invalid_argument(
+                        "simulate",
+                        "either --schedule or --archives is required",
+                    ))
+                }
+                (None, _) => (),
+            }
 
-    fn day_generation(n: usize) -> Generation {
-        Generation {
-            interval: Duration::days(1),
-            count: n,
+            let steps = simulate(
+                &settings.groups,
+                settings.strategy,
+                &settings.protection,
+                backups,
+                &start,
+                &end,
+                step,
+            )?;
+            match opt.format {
+                Format::Text => print!("{}", format_simulation(&steps, &settings.timezone)),
+                Format::Json => println!("{}", serde_json::to_string_pretty(&steps).unwrap()),
+            }
+            Ok(())
         }
+        Some(Command::Hold { ref command }) => manage_holds(opt, policy?, command),
     }
+}
 
-    fn month_generation(n: usize) -> Generation {
-        Generation {
-            interval: Duration::days(30),
-            count: n,
+fn manage_holds(opt: &Opt, policy: PolicyConfig, command: &HoldCommand) -> Result<(), Error> {
+    let path = opt.holds.clone().or_else(|| policy.holds.clone()).ok_or_else(|| {
+        Error::invalid_argument("hold", "requires --holds or holds in the configuration file")
+    })?;
+    let timezone = opt.timezone.or(policy.timezone).unwrap_or(Tz::UTC);
+    let now = &current_time(opt, &timezone);
+    // Adding and removing rewrite the whole file, so they take the lock to
+    // not lose a hold changed by a concurrent run
+    let _lock = match *command {
+        HoldCommand::List => None,
+        _ => Some(acquire_lock(opt, &policy)?),
+    };
+    let mut holds = match *command {
+        HoldCommand::Add { .. } if !path.exists() => Holds::default(),
+        _ => load_holds(&path)?,
+    };
+
+    match *command {
+        HoldCommand::Add { ref archive, expires, ref reason } => {
+            holds.add(Hold {
+                archive: archive.clone(),
+                reason: reason.clone(),
+                expires: expires.map(|t| t.in_timezone(&timezone)),
+                created: *now,
+            });
+            save_holds(&path, &holds)
+        }
+        HoldCommand::Remove { ref archive } => {
+            if !holds.remove(archive) {
+                return Err(Error::invalid_argument(archive.as_str(), "no hold on the archive"));
+            }
+            save_holds(&path, &holds)
+        }
+        HoldCommand::List => {
+            match opt.format {
+                Format::Text => print!("{}", format_holds(&holds, now, &timezone)),
+                Format::Json => {
+                    println!("{}", serde_json::to_string_pretty(&holds.holds).unwrap())
+                }
+            }
+            Ok(())
         }
     }
+}
 
-    fn utc_midnight(year: i32, month: u32, day: u32) -> DateTime<Utc> {
-        Utc.ymd(year, month, day).and_hms(0, 0, 0)
-    }
-
-    #[test]
-    fn filter_empty() {
-        let snapshots: Vec<Snapshot> = vec![];
-        let now = Utc.ymd(2018, 7, 14).and_hms(14, 0, 0);
-        let filtered = filter_by_generation(&snapshots, &day_generation(6), &now);
-
-        assert_eq!(filtered, []);
-    }
-
-    #[test]
-    fn filter_one() {
-        let snapshots = vec![TestSnapshot { ts: utc_midnight(2018, 7, 10) }];
-        let now = Utc.ymd(2018, 7, 14).and_hms(14, 0, 0);
-        let filtered = filter_by_generation(&snapshots, &day_generation(6), &now);
-
-        assert_eq!(filtered, snapshots);
+fn rotate<B: ArchiveBackend>(
+    settings: Settings<B>,
+    now: &DateTime<Utc>,
+    force: bool,
+    format: Format,
+    metrics: &mut Metrics,
+) -> Result<(), Error> {
+    let mut audit_log = match settings.audit_log {
+        Some(ref path) => Some(AuditLog::open(path)?),
+        None => None,
+    };
+    let (snapshots, skipped) = settings.list_snapshots()?;
+    let clock_check = settings.clock_check.check(&snapshots, now);
+    let mut decisions = decide(
+        &settings.groups,
+        settings.strategy,
+        &settings.protection,
+        now,
+        snapshots.clone(),
+    );
+    // The archives without a timestamp are kept, but they are still part
+    // of the report, the audit log, the metrics and the deletion limits
+    decisions.extend(skipped.into_iter().map(|snapshot| Decision {
+        snapshot,
+        group: None,
+        reasons: vec![KeepReason::NoTimestamp],
+    }));
+    let mut report = Report::new(now, settings.dry_run, &decisions);
+    report.expired_holds = settings
+        .protection
+        .holds
+        .expired(now)
+        .into_iter()
+        .cloned()
+        .collect();
+    for hold in &report.expired_holds {
+        warn!("The hold on {} has expired", hold.archive);
     }
-
-    #[test]
-    fn filter_even_intervals() {
-        let snapshots = vec![
-            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 13).and_hms(12, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(12, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 15).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 15).and_hms(12, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(12, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 17).and_hms(12, 0, 0) },
-        ];
-        let now = Utc.ymd(2018, 7, 17).and_hms(14, 0, 0);
-        let expected = vec![
-            TestSnapshot { ts: Utc.ymd(2018, 7, 14).and_hms(12, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 15).and_hms(12, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 7, 16).and_hms(12, 0, 0) },
-        ];
-
-        let filtered = filter_by_generation(&snapshots, &day_generation(3), &now);
-        assert_eq!(filtered, expected);
+    // The decisions are on the disk before anything is deleted
+    if let Some(ref mut log) = audit_log {
+        let policy = describe_policy(&settings.groups, settings.strategy, settings.mode);
+        log.record_decision(&policy, &report)?;
     }
-
-    #[test]
-    fn filter_uneven_intervals() {
-        let snapshots = vec![
-            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 18) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 27) },
-            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
-        ];
-        let now = utc_midnight(2018, 4, 5);
-        let expected = vec![
-            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-        ];
-
-        let filtered = filter_by_generation(&snapshots, &month_generation(4), &now);
-        assert_eq!(filtered, expected);
+    let delete_limit = settings
+        .delete_limit
+        .check(report.summary.delete, report.summary.total);
+    let dry_run = settings.dry_run;
+    let mut backend = settings.backend;
+    let overridable = |res: Result<(), Error>| match res {
+        Err(ref err) if force => {
+            warn!("{}. Deleting anyway because of --force", err);
+            Ok(())
+        }
+        res => res,
+    };
+    let result = match overridable(clock_check)
+        .and_then(|_| overridable(delete_limit))
+    {
+        Ok(()) => {
+            let names = report.names_to_delete();
+            if settings.print_stats && !names.is_empty() {
+                match backend.print_stats(&names) {
+                    Ok(stats) => report.set_storage_before(&stats),
+                    Err(err) => warn!("Failed to read the storage statistics: {}", err),
+                }
+            }
+            let deletion =
+                delete_in_batches(&mut backend, &snapshots, names, dry_run, settings.batch_size);
+            report.set_archive_statuses(&deletion.archives);
+            let deleted = deletion
+                .archives
+                .iter()
+                .any(|x| x.status == ArchiveStatus::Deleted);
+            if report.storage.is_some() && deleted {
+                match backend.print_stats(&[]) {
+                    Ok(stats) => report.set_storage_after(&stats.all),
+                    Err(err) => warn!("Failed to read the storage statistics: {}", err),
+                }
+            }
+            deletion.result
+        }
+        Err(err) => Err(err),
+    };
+    report.set_deletion_result(&result);
+    *metrics = Metrics::from_report(&settings.groups, &report);
+
+    if let Some(ref mut log) = audit_log {
+        if let Err(err) = log.record_result(&report) {
+            warn!("Failed to write the audit log: {}", err);
+        }
     }
-
-    #[test]
-    fn filter_hourly_intervals() {
-        let snapshots = vec![
-            TestSnapshot { ts: Utc.ymd(2018, 1, 1).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 1, 1).and_hms(2, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 1).and_hms(13, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 25, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(1, 10, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(2, 30, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(2, 40, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 15, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 20, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(6, 15, 0) },
-        ];
-        let now = Utc.ymd(2018, 4, 2).and_hms(10, 30, 0);
-        let expected = vec![
-            TestSnapshot { ts: Utc.ymd(2018, 4, 1).and_hms(13, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 0, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(0, 25, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(1, 10, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(2, 30, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(3, 20, 0) },
-            TestSnapshot { ts: Utc.ymd(2018, 4, 2).and_hms(6, 15, 0) },
-        ];
-
-        let filtered = filter_by_generation(&snapshots, &hour_generation(24), &now);
-        assert_eq!(filtered, expected);
+    match format {
+        Format::Json => println!("{}", report.to_json()),
+        Format::Text => print!("{}", format_storage(&report)),
     }
 
-    #[test]
-    fn generation_large_count() {
-        let snapshots = vec![
-            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
-        ];
-        let generation = Generation {
-            interval: Duration::days(30),
-            count: 99,
-        };
-        let now = utc_midnight(2018, 4, 5);
-        let expected = vec![
-            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-        ];
-
-        let filtered = filter_by_generation(&snapshots, &generation, &now);
-        assert_eq!(filtered, expected);
-    }
+    result
+}
 
-    #[test]
-    fn generation_uneven() {
-        let snapshots = vec![
-            TestSnapshot { ts: utc_midnight(2017, 10, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 18) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 27) },
-            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
-        ];
-        let generation = Generation {
-            interval: Duration::days(30),
-            count: 3,
-        };
-        let now = utc_midnight(2018, 4, 5);
-        let expected = vec![
-            TestSnapshot { ts: utc_midnight(2018, 1, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-        ];
-
-        let filtered = filter_by_generation(&snapshots, &generation, &now);
-        assert_eq!(filtered, expected);
+fn load_settings(
+    opt: &Opt,
+    policy: PolicyConfig,
+    generation_args: &[String],
+) -> Result<Settings<Tarsnap>, Error> {
+    // Command line arguments override the configuration file
+    let generation_args = if generation_args.is_empty() {
+        policy.generations.clone()
+    } else {
+        Some(generation_args.to_vec())
+    };
+    let group_configs = if opt.groups.is_empty() {
+        policy.groups.clone()
+    } else {
+        opt.groups.clone()
+    };
+    let rotate_ungrouped = switch(
+        opt.rotate_ungrouped,
+        opt.no_rotate_ungrouped,
+        policy.rotate_ungrouped == Some(true),
+    );
+    let strategy = opt.strategy.or(policy.strategy).unwrap_or(Strategy::Nearest);
+    let fixed_intervals = switch(
+        opt.fixed_intervals,
+        opt.no_fixed_intervals,
+        policy.fixed_intervals == Some(true),
+    );
+    let mode = if fixed_intervals {
+        IntervalMode::Fixed
+    } else {
+        IntervalMode::Calendar
+    };
+    let timezone = opt.timezone.or(policy.timezone).unwrap_or(Tz::UTC);
+    let dry_run = switch(opt.dry_run, opt.no_dry_run, policy.dry_run == Some(true));
+    let print_stats = switch(
+        opt.print_stats,
+        opt.no_print_stats,
+        policy.print_stats == Some(true),
+    );
+    let batch_size = opt.batch_size.or(policy.batch_size);
+    if batch_size == Some(0) {
+        return Err(Error::invalid_argument("--batch-size", "must be at least 1"));
     }
+    let protection = Protection {
+        keep_last: opt.keep_last.or(policy.keep_last).unwrap_or(0),
+        min_age: opt.min_age.or(policy.min_age),
+        patterns: protect_patterns(if opt.protect.is_empty() {
+            policy.protect.clone().unwrap_or_default()
+        } else {
+            opt.protect.clone()
+        })?,
+        pinned: match opt.protect_file.as_ref().or(policy.protect_file.as_ref()) {
+            Some(path) => load_protect_file(path)?,
+            None => Vec::new(),
+        },
+        holds: match opt.holds.as_ref().or(policy.holds.as_ref()) {
+            Some(path) => load_holds(path)?,
+            None => Holds::default(),
+        },
+    };
+    let delete_limit = DeleteLimit {
+        max_count: opt.max_delete.or(policy.max_delete),
+        max_percent: opt.max_delete_percent.or(policy.max_delete_percent),
+    };
+    let clock_check = ClockCheck {
+        max_future: opt.max_future
+            .or(policy.max_future)
+            .or_else(|| Some(Duration::hours(1))),
+        max_newest_age: opt.max_newest_age.or(policy.max_newest_age),
+    };
+    let name_timestamp = match (&opt.timestamp_from_name, &policy.timestamp_from_name) {
+        (Some(parser), _) => Some(parser.clone().with_timezone(timezone)),
+        (None, Some(format)) => Some(format.parse::<NameTimestamp>()?.with_timezone(timezone)),
+        (None, None) => None,
+    };
+    let timestamp_fallback = opt.timestamp_fallback
+        .or(policy.timestamp_fallback)
+        .unwrap_or(TimestampFallback::Error);
+
+    let backend = Tarsnap {
+        binary: opt.tarsnap_path
+            .clone()
+            .or(policy.tarsnap_path)
+            .or_else(|| env::var_os("TARSNAP_LIFESPAN_TARSNAP").map(PathBuf::from))
+            .unwrap_or_else(|| Tarsnap::new().binary),
+        prefix: if opt.tarsnap_prefix.is_empty() {
+            policy.tarsnap_prefix.unwrap_or_default()
+        } else {
+            opt.tarsnap_prefix.clone()
+        },
+        keyfile: opt.keyfile.clone().or(policy.keyfile),
+        delete_keyfile: opt.delete_keyfile.clone().or(policy.delete_keyfile),
+        cachedir: opt.cachedir.clone().or(policy.cachedir),
+        configfile: opt.configfile.clone().or(policy.configfile),
+        extra_args: if opt.tarsnap_args.is_empty() {
+            policy.tarsnap_args.unwrap_or_default()
+        } else {
+            opt.tarsnap_args.clone()
+        },
+        null_output: switch(
+            opt.null_output,
+            opt.no_null_output,
+            policy.null_output != Some(false),
+        ),
+        strict_names: switch(
+            opt.strict_names,
+            opt.no_strict_names,
+            policy.strict_names == Some(true),
+        ),
+    };
+    let audit_log = opt.audit_log.clone().or(policy.audit_log);
+    let groups = build_groups(group_configs, generation_args, rotate_ungrouped, mode, timezone)?;
+    debug!("Parsed groups: {:?}", groups);
+
+    Ok(Settings {
+        groups,
+        strategy,
+        mode,
+        timezone,
+        protection,
+        dry_run,
+        print_stats,
+        batch_size,
+        audit_log,
+        delete_limit,
+        clock_check,
+        name_timestamp,
+        timestamp_fallback,
+        backend,
+    })
+}
 
-    #[test]
-    fn zero_count_generation() {
-        let snapshots = vec![
-            TestSnapshot { ts: utc_midnight(2018, 3, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 4, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 5, 1) },
-            TestSnapshot { ts: utc_midnight(2018, 6, 1) },
-        ];
-        let generation = Generation {
-            interval: Duration::days(30),
-            count: 0,
-        };
-        let now = Utc.ymd(2018, 8, 1).and_hms(12, 0, 0);
-
-        let filtered = filter_by_generation(&snapshots, &generation, &now);
-        assert_eq!(filtered, Vec::new());
+// Without any configured groups, all archives are rotated together using
+// the default generations.
+fn build_groups(
+    group_configs: Vec<GroupConfig>,
+    default_generations: Option<Vec<String>>,
+    rotate_ungrouped: bool,
+    mode: IntervalMode,
+    timezone: Tz,
+) -> Result<Vec<Group>, Error> {
+    let all_group = || {
+        default_generations
+            .clone()
+            .ok_or_else(|| {
+                Error::invalid_argument(
+                    "<generation_args>",
+                    "no generations given on the command line or in the configuration file",
+                )
+            })
+            .and_then(|args| parse_generations(args, mode, timezone))
+            .map(Group::all)
+    };
+
+    if group_configs.is_empty() {
+        return all_group().map(|group| vec![group]);
     }
 
-    #[test]
-    fn generations_empty() {
-        let snapshots = vec![];
-        let now = Utc.ymd(2018, 8, 1).and_hms(12, 0, 0);
-
-        let filtered = keep_generations(&snapshots, &test_generations(), &now);
-        assert_eq!(filtered, HashSet::new());
+    let mut groups = Vec::new();
+    for GroupConfig { name, pattern, generations } in group_configs {
+        let args = generations
+            .or_else(|| default_generations.clone())
+            .ok_or_else(|| {
+                Error::invalid_argument(name.as_str(), "no generations for the group")
+            })?;
+        groups.push(Group {
+            name,
+            pattern: Regex::new(&pattern)
+                .map_err(|err| Error::invalid_argument(pattern.as_str(), err))?,
+            generations: parse_generations(args, mode, timezone)?,
+        });
     }
-
-    #[test]
-    fn generations_1() {
-        let snapshots = vec![
-            Snapshot {
-                name: "two_years_ago".to_string(),
-                ts: utc_midnight(2016, 6, 1),
-            },
-            Snapshot {
-                name: "last_year".to_string(),
-                ts: utc_midnight(2017, 6, 1),
-            },
-            Snapshot {
-                name: "jan_1".to_string(),
-                ts: utc_midnight(2018, 1, 1),
-            },
-            Snapshot {
-                name: "feb_1".to_string(),
-                ts: utc_midnight(2018, 2, 1),
-            },
-            Snapshot {
-                name: "feb_27".to_string(),
-                ts: utc_midnight(2018, 2, 27),
-            },
-            Snapshot {
-                name: "feb_28".to_string(),
-                ts: utc_midnight(2018, 2, 28),
-            },
-            Snapshot {
-                name: "mar_1".to_string(),
-                ts: utc_midnight(2018, 3, 1),
-            },
-            Snapshot {
-                name: "apr_1".to_string(),
-                ts: utc_midnight(2018, 4, 1),
-            },
-            Snapshot {
-                name: "may_1".to_string(),
-                ts: utc_midnight(2018, 5, 1),
-            },
-            Snapshot {
-                name: "jun_1".to_string(),
-                ts: utc_midnight(2018, 6, 1),
-            },
-            Snapshot {
-                name: "jun_2".to_string(),
-                ts: utc_midnight(2018, 6, 2),
-            },
-            Snapshot {
-                name: "jun_3".to_string(),
-                ts: utc_midnight(2018, 6, 3),
-            },
-            Snapshot {
-                name: "jun_4".to_string(),
-                ts: utc_midnight(2018, 6, 4),
-            },
-            Snapshot {
-                name: "jun_5".to_string(),
-                ts: utc_midnight(2018, 6, 5),
-            },
-            Snapshot {
-                name: "jun_6".to_string(),
-                ts: utc_midnight(2018, 6, 6),
-            },
-        ];
-        let now = Utc.ymd(2018, 6, 6).and_hms(16, 0, 0);
-
-        let expected: HashSet<String> = vec![
-            "last_year".to_string(),
-            "feb_1".to_string(),
-            "mar_1".to_string(),
-            "apr_1".to_string(),
-            "may_1".to_string(),
-            "jun_1".to_string(),
-            "jun_2".to_string(),
-            "jun_3".to_string(),
-            "jun_4".to_string(),
-            "jun_5".to_string(),
-            "jun_6".to_string(),
-        ].iter()
-            .cloned()
-            .collect();
-
-        let filtered = keep_generations(&snapshots, &test_generations(), &now);
-        assert_eq!(filtered, expected);
+    if rotate_ungrouped {
+        groups.push(all_group()?);
     }
 
-    #[test]
-    fn generations_2() {
-        let snapshots = vec![
-            Snapshot {
-                name: "two_years_ago".to_string(),
-                ts: utc_midnight(2016, 6, 1),
-            },
-            Snapshot {
-                name: "last_year".to_string(),
-                ts: utc_midnight(2017, 6, 1),
-            },
-            Snapshot {
-                name: "jan_1".to_string(),
-                ts: utc_midnight(2018, 1, 1),
-            },
-            Snapshot {
-                name: "feb_1".to_string(),
-                ts: utc_midnight(2018, 2, 1),
-            },
-            Snapshot {
-                name: "mar_1".to_string(),
-                ts: utc_midnight(2018, 3, 1),
-            },
-            Snapshot {
-                name: "apr_1".to_string(),
-                ts: utc_midnight(2018, 4, 1),
-            },
-            Snapshot {
-                name: "may_1".to_string(),
-                ts: utc_midnight(2018, 5, 1),
-            },
-            Snapshot {
-                name: "jun_6".to_string(),
-                ts: utc_midnight(2018, 6, 6),
-            },
-            Snapshot {
-                name: "jun_7".to_string(),
-                ts: utc_midnight(2018, 6, 7),
-            },
-            Snapshot {
-                name: "jun_8".to_string(),
-                ts: utc_midnight(2018, 6, 8),
-            },
-            Snapshot {
-                name: "jun_9".to_string(),
-                ts: utc_midnight(2018, 6, 9),
-            },
-            Snapshot {
-                name: "jun_10".to_string(),
-                ts: utc_midnight(2018, 6, 10),
-            },
-            Snapshot {
-                name: "jun_11".to_string(),
-                ts: utc_midnight(2018, 6, 11),
-            },
-            Snapshot {
-                name: "jun_12".to_string(),
-                ts: utc_midnight(2018, 6, 12),
-            },
-        ];
-        let now = Utc.ymd(2018, 6, 12).and_hms(16, 0, 0);
-
-        let expected: HashSet<String> = vec![
-            "last_year".to_string(),
-            "feb_1".to_string(),
-            "mar_1".to_string(),
-            "apr_1".to_string(),
-            "may_1".to_string(),
-            "jun_7".to_string(),
-            "jun_8".to_string(),
-            "jun_9".to_string(),
-            "jun_10".to_string(),
-            "jun_11".to_string(),
-            "jun_12".to_string(),
-        ].iter()
-            .cloned()
-            .collect();
-
-        let filtered = keep_generations(&snapshots, &test_generations(), &now);
-        assert_eq!(filtered, expected);
-    }
+    Ok(groups)
+}
 
-    #[test]
-    fn generations_3() {
-        let snapshots = vec![
-            Snapshot {
-                name: "two_years_ago".to_string(),
-                ts: utc_midnight(2016, 6, 1),
-            },
-            Snapshot {
-                name: "last_year".to_string(),
-                ts: utc_midnight(2017, 6, 1),
-            },
-            Snapshot {
-                name: "jan_1".to_string(),
-                ts: utc_midnight(2018, 1, 1),
-            },
-            Snapshot {
-                name: "feb_1".to_string(),
-                ts: utc_midnight(2018, 2, 1),
-            },
-            Snapshot {
-                name: "mar_1".to_string(),
-                ts: utc_midnight(2018, 3, 1),
-            },
-            Snapshot {
-                name: "apr_1".to_string(),
-                ts: utc_midnight(2018, 4, 1),
-            },
-            Snapshot {
-                name: "may_1".to_string(),
-                ts: utc_midnight(2018, 5, 1),
-            },
-            Snapshot {
-                name: "jun_20".to_string(),
-                ts: utc_midnight(2018, 6, 20),
-            },
-            Snapshot {
-                name: "jun_21".to_string(),
-                ts: utc_midnight(2018, 6, 21),
-            },
-            Snapshot {
-                name: "jun_22".to_string(),
-                ts: utc_midnight(2018, 6, 22),
-            },
-            Snapshot {
-                name: "jun_23".to_string(),
-                ts: utc_midnight(2018, 6, 23),
-            },
-            Snapshot {
-                name: "jun_24".to_string(),
-                ts: utc_midnight(2018, 6, 24),
-            },
-            Snapshot {
-                name: "jun_25".to_string(),
-                ts: utc_midnight(2018, 6, 25),
-            },
-            Snapshot {
-                name: "jun_26".to_string(),
-                ts: utc_midnight(2018, 6, 26),
-            },
-        ];
-        let now = Utc.ymd(2018, 6, 26).and_hms(16, 0, 0);
-
-        let expected: HashSet<String> = vec![
-            "last_year".to_string(),
-            "mar_1".to_string(),
-            "apr_1".to_string(),
-            "may_1".to_string(),
-            "jun_20".to_string(),
-            "jun_21".to_string(),
-            "jun_22".to_string(),
-            "jun_23".to_string(),
-            "jun_24".to_string(),
-            "jun_25".to_string(),
-            "jun_26".to_string(),
-        ].iter()
-            .cloned()
-            .collect();
-
-        let filtered = keep_generations(&snapshots, &test_generations(), &now);
-        assert_eq!(filtered, expected);
-    }
+fn protect_patterns(patterns: Vec<String>) -> Result<Vec<Regex>, Error> {
+    patterns
+        .iter()
+        .map(|x| Regex::new(x).map_err(|err| Error::invalid_argument(x.as_str(), err)))
+        .collect()
+}
 
-    #[test]
-    fn generations_after_long_break() {
-        let snapshots = vec![
-            Snapshot {
-                name: "jan_1".to_string(),
-                ts: utc_midnight(2018, 1, 1),
-            },
-            Snapshot {
-                name: "feb_1".to_string(),
-                ts: utc_midnight(2018, 2, 1),
-            },
-            Snapshot {
-                name: "mar_1".to_string(),
-                ts: utc_midnight(2018, 3, 1),
-            },
-        ];
-        let now = Utc.ymd(2018, 12, 1).and_hms(12, 0, 0);
-        let expected: HashSet<String> = vec!["jan_1".to_string(), "mar_1".to_string()]
-            .iter()
-            .cloned()
-            .collect();
-
-        let filtered = keep_generations(&snapshots, &test_generations(), &now);
-        assert_eq!(filtered, expected);
+// A command line switch or its negation overrides the configuration file
+fn switch(on: bool, off: bool, configured: bool) -> bool {
+    if on || off {
+        on
+    } else {
+        configured
     }
+}
 
-    #[test]
-    fn archives_empty_input() {
-        assert_eq!(parse_archives("".to_string()), Ok(Vec::new()));
-    }
+// The lock file and the lock wait are resolved on their own, so that the
+// lock can be taken before the rest of the settings are loaded
+fn acquire_lock(opt: &Opt, policy: &PolicyConfig) -> Result<Lock, Error> {
+    let cachedir = opt.cachedir.as_ref().or(policy.cachedir.as_ref());
+    let path = opt.lock_file
+        .clone()
+        .or_else(|| policy.lock_file.clone())
+        .unwrap_or_else(|| default_lock_path(cachedir.map(|x| x.as_path())));
+    let wait = if opt.no_wait {
+        Some(Duration::zero())
+    } else {
+        opt.lock_wait.or(policy.lock_wait)
+    };
+    Lock::acquire(&path, wait.map(|x| x.to_std().unwrap_or_default()))
+}
 
-    #[test]
-    fn archives_missing_timestamp() {
-        let test_archives = indoc!(
-            "archive-001\t2018-07-22 15:10:48
-             archive-002
-             archive-003\t2018-08-01 10:35:08"
-        ).to_string();
-        assert_eq!(parse_archives(test_archives).is_err(), true);
-    }
+// The current time from --now or the system clock
+fn current_time(opt: &Opt, timezone: &Tz) -> DateTime<Utc> {
+    let now = opt.now.map_or_else(Utc::now, |t| t.in_timezone(timezone));
+    debug!("Current time is {}", now);
+    now
+}
 
-    #[test]
-    fn archives_valid() {
-        let test_archives = indoc!(
-            "archive-001\t2018-07-22 15:10:48
-             archive-002\t2018-07-23 23:43:51
-             archive-003\t2018-08-01 10:35:08"
-        ).to_string();
-        let expected = Ok(vec![
-            Snapshot {
-                name: "archive-001".to_string(),
-                ts: Utc.ymd(2018, 7, 22).and_hms(15, 10, 48),
-            },
-            Snapshot {
-                name: "archive-002".to_string(),
-                ts: Utc.ymd(2018, 7, 23).and_hms(23, 43, 51),
-            },
-            Snapshot {
-                name: "archive-003".to_string(),
-                ts: Utc.ymd(2018, 8, 1).and_hms(10, 35, 8),
-            },
-        ]);
-
-        assert_eq!(parse_archives(test_archives), expected);
+fn load_policy(opt: &Opt) -> Result<PolicyConfig, Error> {
+    match opt.config {
+        Some(ref path) => {
+            let name = opt.policy.as_ref().map_or("default", |x| x.as_str());
+            load_config(path).and_then(|config| config.policy(name).cloned())
+        }
+        None if opt.policy.is_some() => {
+            Err(Error::invalid_argument("--policy", "requires --config"))
+        }
+        None => Ok(PolicyConfig::default()),
     }
 }
@@ -0,0 +1,339 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use chrono::prelude::*;
+use backend::ArchiveStatus;
+use error::Error;
+use generation::KeepReason;
+use group::Group;
+use report::Report;
+
+/// The number of archives that one generation of a group keeps, against
+/// the number it asks for. Fewer filled than requested slots means that
+/// there are gaps in the backups or the history is still short.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlotMetric {
+    pub group: String,
+    pub generation: String,
+    pub requested: usize,
+    pub filled: usize,
+}
+
+/// Metrics of one rotation run for the Prometheus node_exporter textfile
+/// collector. Missing values are left out of the output, for example the
+/// archive counts if the archives couldn't be listed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub archives_before: Option<usize>,
+    pub archives_after: Option<usize>,
+    pub deleted: Option<usize>,
+    /// The newest and the oldest archive after the run.
+    pub newest: Option<DateTime<Utc>>,
+    pub oldest: Option<DateTime<Utc>>,
+    pub slots: Vec<SlotMetric>,
+    pub duration: Duration,
+    pub success: bool,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+impl Metrics {
+    /// Collect the archive and slot metrics from the report of a run.
+    pub fn from_report(groups: &[Group], report: &Report) -> Metrics {
+        let remaining: Vec<DateTime<Utc>> = report
+            .snapshots
+            .iter()
+            .filter(|x| x.status != Some(ArchiveStatus::Deleted))
+            .map(|x| x.timestamp)
+            .collect();
+        let slots = groups
+            .iter()
+            .flat_map(|group| {
+                group.generations.iter().map(move |generation| {
+                    let name = generation.to_string();
+                    let filled = report
+                        .snapshots
+                        .iter()
+                        .filter(|x| x.group.as_ref() == Some(&group.name))
+                        .filter(|x| {
+                            x.reasons.iter().any(|reason| match *reason {
+                                KeepReason::Generation { ref generation, .. } => {
+                                    *generation == name
+                                }
+                                _ => false,
+                            })
+                        })
+                        .count();
+                    SlotMetric {
+                        group: group.name.clone(),
+                        generation: name,
+                        requested: generation.count,
+                        filled,
+                    }
+                })
+            })
+            .collect();
+
+        Metrics {
+            archives_before: Some(report.snapshots.len()),
+            archives_after: Some(remaining.len()),
+            deleted: Some(report.snapshots.len() - remaining.len()),
+            newest: remaining.iter().max().cloned(),
+            oldest: remaining.iter().min().cloned(),
+            slots,
+            ..Metrics::default()
+        }
+    }
+
+    /// Format the metrics in the OpenMetrics text format.
+    pub fn to_openmetrics(&self) -> String {
+        let mut out = String::new();
+        {
+            let mut gauge = |name: &str, help: &str, values: Vec<(String, String)>| {
+                if values.is_empty() {
+                    return;
+                }
+                let name = format!("tarsnap_lifespan_{}", name);
+                writeln!(out, "# HELP {} {}", name, help).unwrap();
+                writeln!(out, "# TYPE {} gauge", name).unwrap();
+                for (labels, value) in values {
+                    writeln!(out, "{}{} {}", name, labels, value).unwrap();
+                }
+            };
+            let single = |value: Option<String>| {
+                value.map(|x| (String::new(), x)).into_iter().collect()
+            };
+            let seconds = |t: &Option<DateTime<Utc>>| t.map(|x| x.timestamp().to_string());
+            let slots = |value: &dyn Fn(&SlotMetric) -> usize| {
+                self.slots
+                    .iter()
+                    .map(|x| {
+                        let labels = format!(
+                            "{{group=\"{}\",generation=\"{}\"}}",
+                            escape_label(&x.group),
+                            x.generation
+                        );
+                        (labels, value(x).to_string())
+                    })
+                    .collect()
+            };
+
+            gauge(
+                "archives_before",
+                "Number of archives before the run.",
+                single(self.archives_before.map(|x| x.to_string())),
+            );
+            gauge(
+                "archives_after",
+                "Number of archives after the run.",
+                single(self.archives_after.map(|x| x.to_string())),
+            );
+            gauge(
+                "archives_deleted",
+                "Number of archives deleted by the run.",
+                single(self.deleted.map(|x| x.to_string())),
+            );
+            gauge(
+                "newest_archive_timestamp_seconds",
+                "Creation time of the newest archive.",
+                single(seconds(&self.newest)),
+            );
+            gauge(
+                "oldest_archive_timestamp_seconds",
+                "Creation time of the oldest archive.",
+                single(seconds(&self.oldest)),
+            );
+            gauge(
+                "generation_slots_requested",
+                "Number of archives a generation keeps.",
+                slots(&|x| x.requested),
+            );
+            gauge(
+                "generation_slots_filled",
+                "Number of archives kept by a generation.",
+                slots(&|x| x.filled),
+            );
+            gauge(
+                "run_duration_seconds",
+                "Duration of the run.",
+                single(Some(format!("{:.3}", self.duration.as_secs_f64()))),
+            );
+            gauge(
+                "success",
+                "1 if the run succeeded, 0 otherwise.",
+                single(Some(if self.success { "1" } else { "0" }.to_string())),
+            );
+            gauge(
+                "last_run_timestamp_seconds",
+                "Time when the run finished.",
+                single(seconds(&self.finished)),
+            );
+        }
+        out.push_str("# EOF\n");
+        out
+    }
+}
+
+/// Write the metrics to `path`. The file is replaced atomically, so that
+/// the textfile collector never reads a partial file.
+pub fn write_metrics<P: AsRef<Path>>(path: P, metrics: &Metrics) -> Result<(), Error> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(metrics.to_openmetrics().as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|err: io::Error| Error::io(path, err))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use chrono_tz::Tz;
+    use generation::{IntervalMode, parse_generations};
+    use group::Decision;
+    use snapshot::Snapshot;
+
+    fn test_report() -> Report {
+        let decision = |name: &str, day: u32, reasons| Decision {
+            snapshot: Snapshot {
+                name: name.to_string(),
+                ts: Utc.ymd(2018, 7, day).and_hms(0, 0, 0),
+            },
+            group: Some("all".to_string()),
+            reasons,
+        };
+        let slot = |slot| KeepReason::Generation {
+            generation: "3D".to_string(),
+            slot,
+        };
+        let mut report = Report::new(
+            &Utc.ymd(2018, 7, 20).and_hms(0, 0, 0),
+            false,
+            &[
+                decision("a", 10, vec![]),
+                decision("b", 17, vec![slot(3)]),
+                decision("c", 19, vec![slot(1), KeepReason::Latest]),
+            ],
+        );
+        report.snapshots[0].status = Some(ArchiveStatus::Deleted);
+        report
+    }
+
+    #[test]
+    fn metrics_from_report() {
+        let generations =
+            parse_generations(vec!["3D".to_string()], IntervalMode::Calendar, Tz::UTC).unwrap();
+        let metrics = Metrics::from_report(&[Group::all(generations)], &test_report());
+
+        assert_eq!(metrics.archives_before, Some(3));
+        assert_eq!(metrics.archives_after, Some(2));
+        assert_eq!(metrics.deleted, Some(1));
+        assert_eq!(metrics.oldest, Some(Utc.ymd(2018, 7, 17).and_hms(0, 0, 0)));
+        assert_eq!(metrics.newest, Some(Utc.ymd(2018, 7, 19).and_hms(0, 0, 0)));
+        assert_eq!(
+            metrics.slots,
+            vec![SlotMetric {
+                group: "all".to_string(),
+                generation: "3D".to_string(),
+                requested: 3,
+                filled: 2,
+            }]
+        );
+    }
+
+    #[test]
+    fn openmetrics() {
+        let metrics = Metrics {
+            archives_before: Some(3),
+            newest: Some(Utc.ymd(2018, 7, 19).and_hms(0, 0, 0)),
+            slots: vec![SlotMetric {
+                group: "db \"main\"".to_string(),
+                generation: "3D".to_string(),
+                requested: 3,
+                filled: 2,
+            }],
+            duration: Duration::from_millis(1500),
+            success: true,
+            ..Metrics::default()
+        };
+
+        assert_eq!(
+            metrics.to_openmetrics(),
+            indoc!(
+                r#"
+                # HELP tarsnap_lifespan_archives_before Number of archives before the run.
+                # TYPE tarsnap_lifespan_archives_before gauge
+                tarsnap_lifespan_archives_before 3
+                # HELP tarsnap_lifespan_newest_archive_timestamp_seconds Creation time of the newest archive.
+                # TYPE tarsnap_lifespan_newest_archive_timestamp_seconds gauge
+                tarsnap_lifespan_newest_archive_timestamp_seconds 1531958400
+                # HELP tarsnap_lifespan_generation_slots_requested Number of archives a generation keeps.
+                # TYPE tarsnap_lifespan_generation_slots_requested gauge
+                tarsnap_lifespan_generation_slots_requested{group="db \"main\"",generation="3D"} 3
+                # HELP tarsnap_lifespan_generation_slots_filled Number of archives kept by a generation.
+                # TYPE tarsnap_lifespan_generation_slots_filled gauge
+                tarsnap_lifespan_generation_slots_filled{group="db \"main\"",generation="3D"} 2
+                # HELP tarsnap_lifespan_run_duration_seconds Duration of the run.
+                # TYPE tarsnap_lifespan_run_duration_seconds gauge
+                tarsnap_lifespan_run_duration_seconds 1.500
+                # HELP tarsnap_lifespan_success 1 if the run succeeded, 0 otherwise.
+                # TYPE tarsnap_lifespan_success gauge
+                tarsnap_lifespan_success 1
+                # EOF
+                "#
+            )
+            .trim_start()
+        );
+    }
+
+    #[test]
+    fn write_atomically() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("tarsnap.prom");
+
+        write_metrics(&path, &Metrics::default()).unwrap();
+
+        assert!(fs::read_to_string(&path).unwrap().ends_with("# EOF\n"));
+        assert!(!dir.path().join("tarsnap.prom.tmp").exists());
+    }
+}
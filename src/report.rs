@@ -0,0 +1,319 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::str::FromStr;
+use chrono::prelude::*;
+use serde_json;
+use backend::{ArchiveDeletion, ArchiveStatus};
+use error::Error;
+use generation::KeepReason;
+use group::Decision;
+use hold::Hold;
+use stats::{StorageReport, StorageSizes, StorageStats};
+
+/// Output format of the command line tool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Log messages only.
+    Text,
+    /// A JSON report on stdout.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(Error::invalid_argument(s, "unknown format")),
+        }
+    }
+}
+
+/// What is done with a snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Keep,
+    Delete,
+}
+
+/// The retention decision of one snapshot.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SnapshotReport {
+    pub name: String,
+    pub timestamp: DateTime<Utc>,
+    pub group: Option<String>,
+    pub action: Action,
+    pub reasons: Vec<KeepReason>,
+    /// What happened to the snapshot if its deletion was attempted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ArchiveStatus>,
+    /// The sizes from `tarsnap --print-stats` of a snapshot to be
+    /// deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageSizes>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Summary {
+    pub total: usize,
+    pub keep: usize,
+    pub delete: usize,
+}
+
+/// The outcome of deleting the expired snapshots.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum DeletionResult {
+    Deleted,
+    DryRun,
+    NothingToDelete,
+    Failed { error: String, exit_code: i32 },
+}
+
+/// A machine-readable report of a rotation run.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub now: DateTime<Utc>,
+    pub dry_run: bool,
+    pub snapshots: Vec<SnapshotReport>,
+    pub summary: Summary,
+    /// Holds that have expired and no longer protect their archives.
+    pub expired_holds: Vec<Hold>,
+    /// None until the deletion has been attempted.
+    pub deletion: Option<DeletionResult>,
+    /// The storage freed by the deletion. Only with --print-stats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageReport>,
+}
+
+impl Report {
+    /// Create a report of the decisions. The snapshots are listed from the
+    /// oldest to the newest.
+    pub fn new(now: &DateTime<Utc>, dry_run: bool, decisions: &[Decision]) -> Report {
+        let mut snapshots: Vec<SnapshotReport> = decisions
+            .iter()
+            .map(|x| SnapshotReport {
+                name: x.snapshot.name.clone(),
+                timestamp: x.snapshot.ts,
+                group: x.group.clone(),
+                action: if x.keep() { Action::Keep } else { Action::Delete },
+                reasons: x.reasons.clone(),
+                status: None,
+                storage: None,
+            })
+            .collect();
+        snapshots.sort_by(|a, b| (a.timestamp, &a.name).cmp(&(b.timestamp, &b.name)));
+        let delete = snapshots.iter().filter(|x| x.action == Action::Delete).count();
+
+        Report {
+            now: *now,
+            dry_run,
+            summary: Summary {
+                total: snapshots.len(),
+                keep: snapshots.len() - delete,
+                delete,
+            },
+            snapshots,
+            expired_holds: Vec::new(),
+            deletion: None,
+            storage: None,
+        }
+    }
+
+    /// The names of the snapshots to be deleted.
+    pub fn names_to_delete(&self) -> Vec<String> {
+        self.snapshots
+            .iter()
+            .filter(|x| x.action == Action::Delete)
+            .map(|x| x.name.clone())
+            .collect()
+    }
+
+    /// Record the status of each archive after `delete_in_batches`.
+    pub fn set_archive_statuses(&mut self, archives: &[ArchiveDeletion]) {
+        for archive in archives {
+            if let Some(snapshot) = self.snapshots.iter_mut().find(|x| x.name == archive.name) {
+                snapshot.status = Some(archive.status);
+            }
+        }
+    }
+
+    /// Record the sizes of the snapshots to be deleted and of all archives
+    /// before the deletion.
+    pub fn set_storage_before(&mut self, stats: &StorageStats) {
+        let mut expected = StorageSizes::default();
+        for &(ref name, sizes) in &stats.archives {
+            let snapshot = self.snapshots
+                .iter_mut()
+                .find(|x| &x.name == name && x.action == Action::Delete);
+            if let Some(snapshot) = snapshot {
+                snapshot.storage = Some(sizes);
+                expected = expected + sizes;
+            }
+        }
+
+        self.storage = Some(StorageReport {
+            before: stats.all,
+            after: None,
+            expected,
+            freed: None,
+        });
+    }
+
+    /// Record the sizes of all archives after the deletion.
+    pub fn set_storage_after(&mut self, after: &StorageSizes) {
+        if let Some(ref mut storage) = self.storage {
+            storage.after = Some(*after);
+            storage.freed = Some(storage.before.saturating_sub(after));
+        }
+    }
+
+    /// Record the result of the deletion.
+    pub fn set_deletion_result(&mut self, result: &Result<(), Error>) {
+        self.deletion = Some(match *result {
+            Err(ref err) => DeletionResult::Failed {
+                error: err.to_string(),
+                exit_code: err.exit_code(),
+            },
+            Ok(()) if self.summary.delete == 0 => DeletionResult::NothingToDelete,
+            Ok(()) if self.dry_run => DeletionResult::DryRun,
+            Ok(()) => DeletionResult::Deleted,
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use snapshot::Snapshot;
+
+    fn decision(name: &str, day: u32, reasons: Vec<KeepReason>) -> Decision {
+        Decision {
+            snapshot: Snapshot {
+                name: name.to_string(),
+                ts: Utc.ymd(2018, 7, day).and_hms(12, 0, 0),
+            },
+            group: Some("db".to_string()),
+            reasons,
+        }
+    }
+
+    fn test_report(dry_run: bool) -> Report {
+        let now = Utc.ymd(2018, 7, 20).and_hms(0, 0, 0);
+        Report::new(
+            &now,
+            dry_run,
+            &[
+                decision("c", 19, vec![KeepReason::Latest]),
+                decision("a", 10, vec![]),
+                decision("b", 11, vec![]),
+            ],
+        )
+    }
+
+    #[test]
+    fn summary() {
+        let report = test_report(false);
+
+        assert_eq!(report.summary, Summary { total: 3, keep: 1, delete: 2 });
+        assert_eq!(report.names_to_delete(), vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn deletion_result() {
+        let mut report = test_report(true);
+        report.set_deletion_result(&Ok(()));
+        assert_eq!(report.deletion, Some(DeletionResult::DryRun));
+
+        report.set_deletion_result(&Err(Error::ArchiveNotFound("a".to_string())));
+        assert_eq!(
+            report.deletion,
+            Some(DeletionResult::Failed {
+                error: "Archive does not exist: a".to_string(),
+                exit_code: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn json() {
+        let mut report = test_report(false);
+        report.set_archive_statuses(&[ArchiveDeletion {
+            name: "a".to_string(),
+            status: ArchiveStatus::Deleted,
+        }]);
+        report.set_deletion_result(&Ok(()));
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+
+        assert_eq!(json["now"], "2018-07-20T00:00:00Z");
+        assert_eq!(json["snapshots"][2]["name"], "c");
+        assert_eq!(json["snapshots"][2]["timestamp"], "2018-07-19T12:00:00Z");
+        assert_eq!(json["snapshots"][2]["group"], "db");
+        assert_eq!(json["snapshots"][2]["action"], "keep");
+        assert_eq!(json["snapshots"][2]["reasons"][0]["rule"], "latest");
+        assert_eq!(json["snapshots"][0]["action"], "delete");
+        assert_eq!(json["snapshots"][0]["status"], "deleted");
+        assert!(json["snapshots"][1].get("status").is_none());
+        assert_eq!(json["summary"]["delete"], 2);
+        assert_eq!(json["deletion"]["status"], "deleted");
+        assert!(json.get("storage").is_none());
+    }
+
+    #[test]
+    fn storage() {
+        let sizes = |total_size, unique_size| StorageSizes {
+            total_size,
+            compressed_size: total_size / 2,
+            unique_size,
+            unique_compressed_size: unique_size / 2,
+        };
+        let mut report = test_report(false);
+        report.set_storage_before(&StorageStats {
+            all: sizes(1000, 600),
+            archives: vec![("a".to_string(), sizes(100, 20)), ("b".to_string(), sizes(200, 40))],
+        });
+        report.set_storage_after(&sizes(700, 500));
+
+        assert_eq!(report.snapshots[0].storage, Some(sizes(100, 20)));
+        assert_eq!(
+            report.storage,
+            Some(StorageReport {
+                before: sizes(1000, 600),
+                after: Some(sizes(700, 500)),
+                expected: sizes(300, 60),
+                freed: Some(sizes(300, 100)),
+            })
+        );
+    }
+}
@@ -0,0 +1,516 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::str::FromStr;
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use serde::Serializer;
+use backend::{ArchiveBackend, MemoryBackend, delete_snapshots};
+use duration::format_duration;
+use error::Error;
+use generation::Strategy;
+use group::{Group, Protection, decide};
+use snapshot::Snapshot;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// A backup schedule in cron format: minute, hour, day of month, month and
+/// day of week, for example "0 3 * * *".
+///
+/// Each field is `*` or a comma separated list of numbers and ranges,
+/// such as `1-5`, optionally followed by a step, such as `*/15`. Sunday
+/// is 0 or 7. As in cron, if both the day of month and the day of week
+/// are restricted, a time matches if either of them matches. The fields
+/// are matched against the local time of the time zone that is given to
+/// `times`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronSchedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CronSchedule, Error> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(Error::invalid_argument(s, "expected five cron fields"));
+        }
+
+        let parse = |field: &str, min: usize, max: usize| {
+            parse_cron_field(field, min, max).map_err(|err| Error::invalid_argument(s, err))
+        };
+        let mut weekdays = parse(fields[4], 0, 7)?;
+        // 7 is an alias for Sunday
+        weekdays[0] = weekdays[0] || weekdays[7];
+        weekdays.truncate(7);
+
+        Ok(CronSchedule {
+            minutes: parse(fields[0], 0, 59)?,
+            hours: parse(fields[1], 0, 23)?,
+            days: parse(fields[2], 1, 31)?,
+            months: parse(fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+}
+
+impl CronSchedule {
+    /// Does the schedule fire at the minute of `t`?
+    pub fn matches<T: TimeZone>(&self, t: &DateTime<T>) -> bool {
+        self.date_matches(&t.naive_local().date()) && self.minutes[t.minute() as usize]
+            && self.hours[t.hour() as usize]
+    }
+
+    fn date_matches(&self, date: &NaiveDate) -> bool {
+        let day = self.days[date.day() as usize];
+        let weekday = self.weekdays[date.weekday().num_days_from_sunday() as usize];
+        let day_matches = match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        };
+
+        self.months[date.month() as usize] && day_matches
+    }
+
+    /// The times when the schedule fires between `start` and `end`,
+    /// inclusive, in the local time of `timezone`. A local time that is
+    /// repeated in autumn fires twice and a skipped one never fires.
+    pub fn times(
+        &self,
+        start: &DateTime<Utc>,
+        end: &DateTime<Utc>,
+        timezone: &Tz,
+    ) -> Vec<DateTime<Utc>> {
+        let mut t = start.date().and_hms(start.hour(), start.minute(), 0);
+        if t < *start {
+            t = t + Duration::minutes(1);
+        }
+
+        let mut times = Vec::new();
+        while t <= *end {
+            let local = t.with_timezone(timezone);
+            if self.matches(&local) {
+                times.push(t);
+            }
+
+            // Jump to the next local time that may match. If the UTC
+            // offset changes on the way, stop at the change instead, so
+            // that the local times repeated in autumn are not skipped.
+            t = match self.next_candidate(&local.naive_local()) {
+                Some(next) => {
+                    let offset = |t: &DateTime<Utc>| t.with_timezone(timezone).offset().fix();
+                    let jump = t + next.signed_duration_since(local.naive_local());
+                    let (mut before, mut after) = (t, jump);
+                    while offset(&after) != local.offset().fix()
+                        && after - before > Duration::minutes(1)
+                    {
+                        let middle = before + Duration::minutes((after - before).num_minutes() / 2);
+                        if offset(&middle) == local.offset().fix() {
+                            before = middle;
+                        } else {
+                            after = middle;
+                        }
+                    }
+                    after
+                }
+                None => t + Duration::minutes(1),
+            };
+        }
+
+        times
+    }
+
+    // The first local time after `t` that is not ruled out by the fields
+    // that `t` fails to match
+    fn next_candidate(&self, t: &NaiveDateTime) -> Option<NaiveDateTime> {
+        let date = t.date();
+        let next_day = || date.succ_opt().map(|d| d.and_hms(0, 0, 0));
+        let next_hour = || match (t.hour() + 1..24).find(|&h| self.hours[h as usize]) {
+            Some(h) => Some(date.and_hms(h, 0, 0)),
+            None => next_day(),
+        };
+
+        if !self.months[date.month() as usize] {
+            let (year, month) = if date.month() == 12 {
+                (date.year() + 1, 1)
+            } else {
+                (date.year(), date.month() + 1)
+            };
+            NaiveDate::from_ymd_opt(year, month, 1).map(|d| d.and_hms(0, 0, 0))
+        } else if !self.date_matches(&date) {
+            next_day()
+        } else if !self.hours[t.hour() as usize] {
+            next_hour()
+        } else {
+            match (t.minute() + 1..60).find(|&m| self.minutes[m as usize]) {
+                Some(m) => Some(date.and_hms(t.hour(), m, 0)),
+                None => next_hour(),
+            }
+        }
+    }
+}
+
+// Parse one cron field into a table that tells which values in 0..=max
+// are included
+fn parse_cron_field(field: &str, min: usize, max: usize) -> Result<Vec<bool>, String> {
+    let number = |s: &str| -> Result<usize, String> {
+        match s.parse::<usize>() {
+            Ok(n) if n >= min && n <= max => Ok(n),
+            _ => Err(format!("invalid value {} in {}", s, field)),
+        }
+    };
+
+    let mut included = vec![false; max + 1];
+    for item in field.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(i) => (&item[..i], number(&item[i + 1..]).ok().filter(|&x| x > 0)),
+            None => (item, Some(1)),
+        };
+        let step = step.ok_or_else(|| format!("invalid step in {}", field))?;
+        let (first, last) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(i) => {
+                    let (first, last) = (number(&range[..i])?, number(&range[i + 1..])?);
+                    if first > last {
+                        return Err(format!("inverted range {} in {}", range, field));
+                    }
+                    (first, last)
+                }
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            }
+        };
+        for value in (first..(last + 1)).step_by(step) {
+            included[value] = true;
+        }
+    }
+
+    Ok(included)
+}
+
+/// The snapshots that a backup schedule in the local time of `timezone`
+/// creates between `start` and `end`. They are named "backup-<timestamp>"
+/// with the timestamp in UTC.
+pub fn scheduled_snapshots(
+    schedule: &CronSchedule,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+    timezone: &Tz,
+) -> Vec<Snapshot> {
+    schedule
+        .times(start, end, timezone)
+        .into_iter()
+        .map(|ts| Snapshot {
+            name: format!("backup-{}", ts.format("%Y-%m-%d_%H-%M-%S")),
+            ts,
+        })
+        .collect()
+}
+
+/// The archives after one step of the simulation.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SimulationStep {
+    pub now: DateTime<Utc>,
+    /// The number of archives left after the rotation.
+    pub archives: usize,
+    /// The number of archives deleted on this step.
+    pub deleted: usize,
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    /// The longest time between two consecutive archives.
+    #[serde(rename = "worst_gap_seconds", serialize_with = "serialize_seconds")]
+    pub worst_gap: Option<Duration>,
+}
+
+fn serialize_seconds<S: Serializer>(
+    d: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match *d {
+        Some(d) => serializer.serialize_some(&d.num_seconds()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Replay a policy over a backup history.
+///
+/// The rotation is run every `step` from `start + step` until `end`. Before
+/// each run, the `backups` taken by then are added to the archives.
+pub fn simulate(
+    groups: &[Group],
+    strategy: Strategy,
+    protection: &Protection,
+    backups: Vec<Snapshot>,
+    start: &DateTime<Utc>,
+    end: &DateTime<Utc>,
+    step: Duration,
+) -> Result<Vec<SimulationStep>, Error> {
+    if step <= Duration::zero() {
+        return Err(Error::invalid_argument("--step", "must be positive"));
+    }
+
+    let mut pending = backups;
+    pending.sort_by_key(|x| Reverse(x.ts));
+    let mut backend = MemoryBackend::new(Vec::new());
+    let mut steps = Vec::new();
+    let mut next = start.checked_add_signed(step);
+    while let Some(now) = next.filter(|t| t <= end) {
+        while pending.last().is_some_and(|x| x.ts <= now) {
+            backend.archives.extend(pending.pop());
+        }
+
+        let snapshots = backend.list_archives()?;
+        let names: Vec<String> = decide(groups, strategy, protection, &now, snapshots)
+            .into_iter()
+            .filter(|x| !x.keep())
+            .map(|x| x.snapshot.name)
+            .collect();
+        let deleted = names.len();
+        delete_snapshots(&mut backend, names, false)?;
+
+        let mut times: Vec<DateTime<Utc>> = backend.archives.iter().map(|x| x.ts).collect();
+        times.sort();
+        steps.push(SimulationStep {
+            now,
+            archives: times.len(),
+            deleted,
+            oldest: times.first().cloned(),
+            newest: times.last().cloned(),
+            worst_gap: times.windows(2).map(|w| w[1] - w[0]).max(),
+        });
+
+        next = now.checked_add_signed(step);
+    }
+
+    Ok(steps)
+}
+
+/// Format the simulation steps as a table followed by a summary. The
+/// times are shown in `timezone`.
+pub fn format_simulation(steps: &[SimulationStep], timezone: &Tz) -> String {
+    let local = |t: &DateTime<Utc>| t.with_timezone(timezone).format(TIME_FORMAT);
+    let age = |now: &DateTime<Utc>, t: Option<DateTime<Utc>>| {
+        t.map_or("-".to_string(), |t| format_duration(*now - t))
+    };
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<19}  {:>8}  {:>7}  {:>12}  {:>12}  {:>12}",
+        "Time", "Archives", "Deleted", "Newest age", "Oldest age", "Worst gap"
+    ).unwrap();
+    for step in steps {
+        writeln!(
+            out,
+            "{:<19}  {:>8}  {:>7}  {:>12}  {:>12}  {:>12}",
+            local(&step.now),
+            step.archives,
+            step.deleted,
+            age(&step.now, step.newest),
+            age(&step.now, step.oldest),
+            step.worst_gap.map_or("-".to_string(), format_duration)
+        ).unwrap();
+    }
+
+    if let Some(most) = steps.iter().rev().max_by_key(|x| x.archives) {
+        writeln!(
+            out,
+            "\nMost archives: {} at {}",
+            most.archives,
+            local(&most.now)
+        ).unwrap();
+    }
+    let worst = steps
+        .iter()
+        .rev()
+        .filter(|x| x.worst_gap.is_some())
+        .max_by_key(|x| x.worst_gap);
+    if let Some(worst) = worst {
+        writeln!(
+            out,
+            "Worst gap: {} at {}",
+            worst.worst_gap.map_or("-".to_string(), format_duration),
+            local(&worst.now)
+        ).unwrap();
+    }
+    if let Some(last) = steps.last() {
+        writeln!(out, "Oldest archive at the end: {}", age(&last.now, last.oldest)).unwrap();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Tz;
+    use generation::{Generation, IntervalMode, Unit};
+
+    #[test]
+    fn cron_fields() {
+        let schedule: CronSchedule = "*/15 3,15 * * 1-5".parse().unwrap();
+        // Monday
+        assert!(schedule.matches(&Utc.ymd(2018, 7, 16).and_hms(3, 45, 0)));
+        assert!(schedule.matches(&Utc.ymd(2018, 7, 16).and_hms(15, 0, 0)));
+        assert!(!schedule.matches(&Utc.ymd(2018, 7, 16).and_hms(3, 10, 0)));
+        assert!(!schedule.matches(&Utc.ymd(2018, 7, 16).and_hms(4, 0, 0)));
+        // Sunday
+        assert!(!schedule.matches(&Utc.ymd(2018, 7, 15).and_hms(3, 0, 0)));
+
+        assert!("0 3 * *".parse::<CronSchedule>().is_err());
+        assert!("60 3 * * *".parse::<CronSchedule>().is_err());
+        assert!("*/0 3 * * *".parse::<CronSchedule>().is_err());
+    }
+
+    #[test]
+    fn cron_inverted_range() {
+        match "0 3 * * 5-1".parse::<CronSchedule>() {
+            Err(Error::InvalidArgument { reason, .. }) => {
+                assert_eq!(reason, "inverted range 5-1 in 5-1")
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!("0 3 * * 1-1".parse::<CronSchedule>().is_ok());
+    }
+
+    #[test]
+    fn cron_long_range() {
+        // Twice a year for a thousand years
+        let schedule: CronSchedule = "15 4 1 1,7 *".parse().unwrap();
+        let times = schedule.times(
+            &Utc.ymd(2018, 1, 1).and_hms(0, 0, 0),
+            &Utc.ymd(3017, 12, 31).and_hms(0, 0, 0),
+            &Tz::Europe__Helsinki,
+        );
+
+        assert_eq!(times.len(), 2000);
+        assert_eq!(times[0], Utc.ymd(2018, 1, 1).and_hms(2, 15, 0));
+        assert_eq!(times[1], Utc.ymd(2018, 7, 1).and_hms(1, 15, 0));
+    }
+
+    #[test]
+    fn cron_day_of_month_or_week() {
+        // The 1st of the month or any Sunday
+        let schedule: CronSchedule = "0 0 1 * 7".parse().unwrap();
+        assert!(schedule.matches(&Utc.ymd(2018, 7, 1).and_hms(0, 0, 0)));
+        assert!(schedule.matches(&Utc.ymd(2018, 7, 8).and_hms(0, 0, 0)));
+        assert!(!schedule.matches(&Utc.ymd(2018, 7, 9).and_hms(0, 0, 0)));
+
+        let times = schedule.times(
+            &Utc.ymd(2018, 7, 1).and_hms(0, 0, 30),
+            &Utc.ymd(2018, 8, 1).and_hms(0, 0, 0),
+            &Tz::UTC,
+        );
+        assert_eq!(times.len(), 5);
+        assert_eq!(times[0], Utc.ymd(2018, 7, 8).and_hms(0, 0, 0));
+    }
+
+    #[test]
+    fn cron_in_timezone() {
+        let schedule: CronSchedule = "30 3 * * *".parse().unwrap();
+        let times = schedule.times(
+            &Utc.ymd(2018, 10, 26).and_hms(1, 0, 0),
+            &Utc.ymd(2018, 10, 29).and_hms(0, 0, 0),
+            &Tz::Europe__Helsinki,
+        );
+
+        // 03:30 is repeated when the summer time ends on October 28
+        assert_eq!(
+            times,
+            vec![
+                Utc.ymd(2018, 10, 27).and_hms(0, 30, 0),
+                Utc.ymd(2018, 10, 28).and_hms(0, 30, 0),
+                Utc.ymd(2018, 10, 28).and_hms(1, 30, 0),
+            ]
+        );
+
+        // 03:30 is skipped when the summer time starts on March 25
+        let times = schedule.times(
+            &Utc.ymd(2018, 3, 24).and_hms(0, 0, 0),
+            &Utc.ymd(2018, 3, 27).and_hms(0, 0, 0),
+            &Tz::Europe__Helsinki,
+        );
+        assert_eq!(
+            times,
+            vec![
+                Utc.ymd(2018, 3, 24).and_hms(1, 30, 0),
+                Utc.ymd(2018, 3, 26).and_hms(0, 30, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn simulate_daily_backups() {
+        let groups = vec![
+            Group::all(vec![
+                Generation {
+                    unit: Unit::Day,
+                    count: 3,
+                    mode: IntervalMode::Calendar,
+                    timezone: Tz::UTC,
+                },
+            ]),
+        ];
+        let start = Utc.ymd(2018, 7, 1).and_hms(0, 0, 0);
+        let end = Utc.ymd(2018, 7, 11).and_hms(0, 0, 0);
+        let schedule: CronSchedule = "0 3 * * *".parse().unwrap();
+        let backups = scheduled_snapshots(&schedule, &start, &end, &Tz::UTC);
+
+        let steps = simulate(
+            &groups,
+            Strategy::Nearest,
+            &Protection::default(),
+            backups,
+            &start,
+            &end,
+            Duration::days(1),
+        ).unwrap();
+
+        assert_eq!(steps.len(), 10);
+        assert_eq!(steps[0].archives, 1);
+        assert_eq!(steps[0].worst_gap, None);
+        let last = &steps[9];
+        assert_eq!(last.archives, 3);
+        assert_eq!(last.newest, Some(Utc.ymd(2018, 7, 10).and_hms(3, 0, 0)));
+        assert_eq!(last.oldest, Some(Utc.ymd(2018, 7, 8).and_hms(3, 0, 0)));
+        assert_eq!(last.worst_gap, Some(Duration::days(1)));
+        assert_eq!(steps.iter().map(|x| x.deleted).sum::<usize>(), 7);
+    }
+}
@@ -0,0 +1,217 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use chrono::prelude::*;
+use chrono::NaiveDateTime;
+use error::Error;
+
+/// Anything that has a creation time and can therefore be rotated.
+pub trait SnapshotTimestamp {
+    fn timestamp(&self) -> DateTime<Utc>;
+}
+
+/// A tarsnap archive: its name and creation time.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Snapshot {
+    pub name: String,
+    pub ts: DateTime<Utc>,
+}
+
+impl SnapshotTimestamp for Snapshot {
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.ts
+    }
+}
+
+/// Parse the snapshot names and creation times from the "tarsnap
+/// --list-archives -v" output.
+///
+/// An archive name that contains a newline can't be told apart from two
+/// rows and fails to parse. Use `parse_null_archives` when possible.
+pub fn parse_archives(archives: String) -> Result<Vec<Snapshot>, Error> {
+    archives
+        .split_terminator('\n')
+        .map(|row| parse_archive_row(row, '\t'))
+        .collect()
+}
+
+/// Parse the snapshot names and creation times from the "tarsnap
+/// --list-archives -v --null-output" output, where the fields are
+/// separated by a null character and the archives by two null
+/// characters. This works with any archive name.
+pub fn parse_null_archives(archives: String) -> Result<Vec<Snapshot>, Error> {
+    archives
+        .split_terminator("\0\0")
+        .map(|row| parse_archive_row(row, '\0'))
+        .collect()
+}
+
+/// Fail if an archive name contains a tab, a newline or another control
+/// character. Such names are ambiguous in the --list-archives output of
+/// old tarsnap versions.
+pub fn check_archive_names(snapshots: &[Snapshot]) -> Result<(), Error> {
+    match snapshots.iter().find(|x| x.name.chars().any(char::is_control)) {
+        Some(snapshot) => Err(Error::Parse {
+            row: snapshot.name.clone(),
+            reason: "the archive name contains control characters".to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Parse one archive of --list-archives -v output. For example:
+// archive-2018-07-16_11-01-03       2018-07-16 11:01:03
+//
+// The timestamp is after the last separator, because the name may contain
+// the separator but the timestamp can't.
+fn parse_archive_row(row: &str, separator: char) -> Result<Snapshot, Error> {
+    let parts: Vec<&str> = row.rsplitn(2, separator).collect();
+    if parts.len() == 2 {
+        parse_local_datetime_from_str(parts[0].trim())
+            .map(|t| {
+                Snapshot {
+                    name: parts[1].to_string(),
+                    ts: t,
+                }
+            })
+            .map_err(|reason| Error::Parse {
+                row: row.to_string(),
+                reason,
+            })
+    } else {
+        Err(Error::Parse {
+            row: row.to_string(),
+            reason: "missing timestamp".to_string(),
+        })
+    }
+}
+
+// Parse timestamp from string such as "2018-07-14 11:15:32"
+fn parse_local_datetime_from_str(s: &str) -> Result<DateTime<Utc>, String> {
+    NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+        .map(|t| DateTime::<Utc>::from_utc(t, Utc))
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archives_empty_input() {
+        assert_eq!(parse_archives("".to_string()), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn archives_missing_timestamp() {
+        let test_archives = indoc!(
+            "archive-001\t2018-07-22 15:10:48
+             archive-002
+             archive-003\t2018-08-01 10:35:08"
+        ).to_string();
+        assert_eq!(
+            parse_archives(test_archives),
+            Err(Error::Parse {
+                row: "archive-002".to_string(),
+                reason: "missing timestamp".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn archives_name_with_tab() {
+        assert_eq!(
+            parse_archives("db\tdump\t2018-07-22 15:10:48\n".to_string()),
+            Ok(vec![Snapshot {
+                name: "db\tdump".to_string(),
+                ts: Utc.ymd(2018, 7, 22).and_hms(15, 10, 48),
+            }])
+        );
+    }
+
+    #[test]
+    fn archives_name_with_newline() {
+        assert!(parse_archives("db\ndump\t2018-07-22 15:10:48\n".to_string()).is_err());
+    }
+
+    #[test]
+    fn null_archives() {
+        let test_archives = "archive-001\x002018-07-22 15:10:48\0\0\
+                             db\tdump\n2018\x002018-07-23 23:43:51\0\0"
+            .to_string();
+        let expected = Ok(vec![
+            Snapshot {
+                name: "archive-001".to_string(),
+                ts: Utc.ymd(2018, 7, 22).and_hms(15, 10, 48),
+            },
+            Snapshot {
+                name: "db\tdump\n2018".to_string(),
+                ts: Utc.ymd(2018, 7, 23).and_hms(23, 43, 51),
+            },
+        ]);
+
+        assert_eq!(parse_null_archives(test_archives), expected);
+        assert_eq!(parse_null_archives("".to_string()), Ok(Vec::new()));
+        assert!(parse_null_archives("archive-001\0\0".to_string()).is_err());
+    }
+
+    #[test]
+    fn strict_names() {
+        let snapshot = |name: &str| Snapshot {
+            name: name.to_string(),
+            ts: Utc.ymd(2018, 7, 22).and_hms(15, 10, 48),
+        };
+
+        assert_eq!(check_archive_names(&[snapshot("db dump-2018")]), Ok(()));
+        assert!(check_archive_names(&[snapshot("db\tdump")]).is_err());
+        assert!(check_archive_names(&[snapshot("db\ndump")]).is_err());
+    }
+
+    #[test]
+    fn archives_valid() {
+        let test_archives = indoc!(
+            "archive-001\t2018-07-22 15:10:48
+             archive-002\t2018-07-23 23:43:51
+             archive-003\t2018-08-01 10:35:08"
+        ).to_string();
+        let expected = Ok(vec![
+            Snapshot {
+                name: "archive-001".to_string(),
+                ts: Utc.ymd(2018, 7, 22).and_hms(15, 10, 48),
+            },
+            Snapshot {
+                name: "archive-002".to_string(),
+                ts: Utc.ymd(2018, 7, 23).and_hms(23, 43, 51),
+            },
+            Snapshot {
+                name: "archive-003".to_string(),
+                ts: Utc.ymd(2018, 8, 1).and_hms(10, 35, 8),
+            },
+        ]);
+
+        assert_eq!(parse_archives(test_archives), expected);
+    }
+}
@@ -0,0 +1,291 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::fmt::Write;
+use std::ops::Add;
+use error::Error;
+use report::{Action, Report};
+
+/// The sizes, in bytes, that `tarsnap --print-stats` reports for one
+/// archive or for all archives together.
+///
+/// The unique sizes count the data that no other archive refers to. For
+/// all archives together, `unique_compressed_size` is the amount of data
+/// that is stored and paid for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StorageSizes {
+    pub total_size: u64,
+    pub compressed_size: u64,
+    pub unique_size: u64,
+    pub unique_compressed_size: u64,
+}
+
+impl StorageSizes {
+    /// Subtract the sizes field by field, stopping at zero.
+    pub fn saturating_sub(&self, other: &StorageSizes) -> StorageSizes {
+        StorageSizes {
+            total_size: self.total_size.saturating_sub(other.total_size),
+            compressed_size: self.compressed_size.saturating_sub(other.compressed_size),
+            unique_size: self.unique_size.saturating_sub(other.unique_size),
+            unique_compressed_size: self.unique_compressed_size
+                .saturating_sub(other.unique_compressed_size),
+        }
+    }
+}
+
+impl Add for StorageSizes {
+    type Output = StorageSizes;
+
+    fn add(self, other: StorageSizes) -> StorageSizes {
+        StorageSizes {
+            total_size: self.total_size + other.total_size,
+            compressed_size: self.compressed_size + other.compressed_size,
+            unique_size: self.unique_size + other.unique_size,
+            unique_compressed_size: self.unique_compressed_size + other.unique_compressed_size,
+        }
+    }
+}
+
+/// The output of `tarsnap --print-stats`: the sizes of all archives and
+/// of the archives that were given with `-f`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorageStats {
+    pub all: StorageSizes,
+    pub archives: Vec<(String, StorageSizes)>,
+}
+
+/// The storage freed by deleting the expired archives.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StorageReport {
+    /// All archives before deleting.
+    pub before: StorageSizes,
+    /// All archives after deleting. `None` if nothing was deleted or the
+    /// statistics couldn't be read.
+    pub after: Option<StorageSizes>,
+    /// The sum of the sizes of the archives to be deleted. The unique
+    /// compressed size is the least amount of storage that deleting them
+    /// frees: data that is shared only by the deleted archives is freed,
+    /// too, but isn't unique to any one of them.
+    pub expected: StorageSizes,
+    /// The difference between `before` and `after`.
+    pub freed: Option<StorageSizes>,
+}
+
+/// Parse the output of `tarsnap --print-stats --no-humanize-numbers`,
+/// for example:
+///
+/// ```text
+///                                        Total size  Compressed size
+/// All archives                               104491            24347
+///   (unique data)                             14175             5549
+/// backup-2018-07-14                            2045              899
+///   (unique data)                              2045              899
+/// ```
+///
+/// The first entry is all archives and the rest are the archives given
+/// with `-f`.
+pub fn parse_stats(output: &str) -> Result<StorageStats, Error> {
+    let parse_error = |row: &str, reason: &str| Error::Parse {
+        row: row.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let mut entries: Vec<(String, StorageSizes)> = Vec::new();
+    for row in output.lines().filter(|x| !x.trim().is_empty()) {
+        if entries.is_empty() && row.trim_start().starts_with("Total size") {
+            continue;
+        }
+
+        let (name, total_size, compressed_size) = split_sizes(row)
+            .ok_or_else(|| parse_error(row, "expected a name and two sizes"))?;
+        if name.trim() == "(unique data)" {
+            let sizes = match entries.last_mut() {
+                Some(&mut (_, ref mut sizes)) => sizes,
+                None => return Err(parse_error(row, "unique data without an archive")),
+            };
+            sizes.unique_size = total_size;
+            sizes.unique_compressed_size = compressed_size;
+        } else {
+            entries.push((
+                name.to_string(),
+                StorageSizes {
+                    total_size,
+                    compressed_size,
+                    ..StorageSizes::default()
+                },
+            ));
+        }
+    }
+
+    if entries.is_empty() {
+        return Err(parse_error(output, "no statistics in the tarsnap output"));
+    }
+    let all = entries.remove(0).1;
+
+    Ok(StorageStats { all, archives: entries })
+}
+
+// Split a row such as "backup-1   2045   899" into the name and the two
+// sizes. The name may contain spaces.
+fn split_sizes(row: &str) -> Option<(&str, u64, u64)> {
+    let (rest, compressed_size) = split_last_size(row)?;
+    let (name, total_size) = split_last_size(rest)?;
+    Some((name.trim_end(), total_size, compressed_size))
+}
+
+fn split_last_size(s: &str) -> Option<(&str, u64)> {
+    let mut parts = s.trim_end().rsplitn(2, char::is_whitespace);
+    let size = parts.next()?.parse().ok()?;
+    parts.next().map(|rest| (rest, size))
+}
+
+/// Format the storage statistics of a report for humans: the compressed
+/// sizes of each archive to be deleted followed by the expected and the
+/// actual savings.
+pub fn format_storage(report: &Report) -> String {
+    let storage = match report.storage {
+        Some(ref storage) => storage,
+        None => return String::new(),
+    };
+    let deleted: Vec<_> = report
+        .snapshots
+        .iter()
+        .filter(|x| x.action == Action::Delete)
+        .collect();
+    let width = deleted
+        .iter()
+        .map(|x| x.name.chars().count())
+        .chain(Some("Archive".len()))
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<width$}  {:>10}  {:>17}",
+        "Archive",
+        "Compressed",
+        "Unique compressed",
+        width = width
+    ).unwrap();
+    for snapshot in deleted {
+        let (compressed, unique) = match snapshot.storage {
+            Some(ref sizes) => (
+                format_bytes(sizes.compressed_size),
+                format_bytes(sizes.unique_compressed_size),
+            ),
+            None => ("-".to_string(), "-".to_string()),
+        };
+        writeln!(
+            out,
+            "{:<width$}  {:>10}  {:>17}",
+            snapshot.name,
+            compressed,
+            unique,
+            width = width
+        ).unwrap();
+    }
+
+    writeln!(
+        out,
+        "\nExpected to free at least {} of the {} stored",
+        format_bytes(storage.expected.unique_compressed_size),
+        format_bytes(storage.before.unique_compressed_size)
+    ).unwrap();
+    if let (Some(after), Some(freed)) = (storage.after, storage.freed) {
+        writeln!(
+            out,
+            "Freed {}, {} stored now",
+            format_bytes(freed.unique_compressed_size),
+            format_bytes(after.unique_compressed_size)
+        ).unwrap();
+    }
+
+    out
+}
+
+// Format a byte count with a decimal prefix, for example "1.5 MB"
+fn format_bytes(bytes: u64) -> String {
+    let units = ["kB", "MB", "GB", "TB", "PB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit + 1 < units.len() {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let output = indoc!(
+            "
+                                                   Total size  Compressed size
+            All archives                               104491            24347
+              (unique data)                             14175             5549
+            backup 2018-07-14                            2045              899
+              (unique data)                              2045              899
+            backup-2018-07-15                            3000             1000
+              (unique data)                                 0                0
+            "
+        );
+
+        let stats = parse_stats(output).unwrap();
+
+        assert_eq!(
+            stats.all,
+            StorageSizes {
+                total_size: 104491,
+                compressed_size: 24347,
+                unique_size: 14175,
+                unique_compressed_size: 5549,
+            }
+        );
+        assert_eq!(stats.archives.len(), 2);
+        assert_eq!(stats.archives[0].0, "backup 2018-07-14");
+        assert_eq!(stats.archives[0].1.unique_compressed_size, 899);
+        assert_eq!(stats.archives[1].0, "backup-2018-07-15");
+        assert_eq!(stats.archives[1].1.compressed_size, 1000);
+        assert!(parse_stats("All archives 2.1 GB 830 MB").is_err());
+        assert!(parse_stats("").is_err());
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(999), "999 B");
+        assert_eq!(format_bytes(1500), "1.5 kB");
+        assert_eq!(format_bytes(830_891_456), "830.9 MB");
+        assert_eq!(format_bytes(2_000_000_000_000_000_000), "2000.0 PB");
+    }
+}
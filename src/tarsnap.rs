@@ -0,0 +1,354 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::process::Output;
+use backend::ArchiveBackend;
+use error::Error;
+use snapshot::{Snapshot, check_archive_names, parse_archives, parse_null_archives};
+use stats::{StorageStats, parse_stats};
+
+const TARSNAP_BINARY: &str = "tarsnap";
+
+/// Archive backend that runs the tarsnap command line client.
+///
+/// `binary` is the tarsnap executable, by default "tarsnap" looked up in
+/// PATH. If `prefix` is not empty, tarsnap is run through the prefix
+/// command, for example `["nice", "-n", "10"]`. The options are passed to
+/// every tarsnap invocation, except that `delete_keyfile`, if set,
+/// replaces `keyfile` when deleting archives. This allows listing the
+/// archives with a read-only key.
+///
+/// The archives are listed with `--null-output` if `null_output` is set
+/// and tarsnap supports it, so that any archive name can be parsed. If
+/// `strict_names` is set, listing fails on archive names that contain
+/// control characters, and also if the archives can't be listed with
+/// `--null-output`.
+#[derive(Clone, Debug)]
+pub struct Tarsnap {
+    pub binary: PathBuf,
+    pub prefix: Vec<String>,
+    pub keyfile: Option<PathBuf>,
+    pub delete_keyfile: Option<PathBuf>,
+    pub cachedir: Option<PathBuf>,
+    pub configfile: Option<PathBuf>,
+    pub extra_args: Vec<String>,
+    pub null_output: bool,
+    pub strict_names: bool,
+}
+
+impl Default for Tarsnap {
+    fn default() -> Tarsnap {
+        Tarsnap {
+            binary: PathBuf::from(TARSNAP_BINARY),
+            prefix: Vec::new(),
+            keyfile: None,
+            delete_keyfile: None,
+            cachedir: None,
+            configfile: None,
+            extra_args: Vec::new(),
+            null_output: true,
+            strict_names: false,
+        }
+    }
+}
+
+impl Tarsnap {
+    pub fn new() -> Tarsnap {
+        Tarsnap::default()
+    }
+
+    // Run "tarsnap --list-archives -v" and return its output. tarsnap
+    // prints the timestamps in the local time zone, so TZ is set to the
+    // POSIX spelling of UTC to get them in UTC whatever the time zone of
+    // the host is.
+    fn list_archives_output(&self, null_output: bool) -> Result<String, Error> {
+        let mut args = self.common_args(&self.keyfile);
+        args.push("--list-archives".into());
+        args.push("-v".into());
+        if null_output {
+            args.push("--null-output".into());
+        }
+        self.run(self.command(&args).env("TZ", "UTC0"))
+            .map(|output| {
+                let archives = String::from_utf8_lossy(&output.stdout).to_string();
+                debug!("Archives list:\n{}", archives.replace('\0', "\\0"));
+
+                archives
+            })
+    }
+
+    // The tarsnap command line with the given arguments, including the
+    // prefix. The tarsnap binary is resolved through PATH.
+    fn command_line(&self, args: &[OsString]) -> Vec<OsString> {
+        let mut words: Vec<OsString> = self.prefix.iter().map(OsString::from).collect();
+        words.push(resolve_binary(&self.binary).into_os_string());
+        words.extend(args.iter().cloned());
+        words
+    }
+
+    fn command(&self, args: &[OsString]) -> Command {
+        let words = self.command_line(args);
+        debug!(
+            "Running {}",
+            words
+                .iter()
+                .map(|x| shell_quote(&x.to_string_lossy()))
+                .collect::<Vec<String>>()
+                .join(" ")
+        );
+
+        let mut command = Command::new(&words[0]);
+        command.args(&words[1..]);
+        command
+    }
+
+    // Run a command and fail unless it exits successfully
+    fn run(&self, command: &mut Command) -> Result<Output, Error> {
+        command
+            .output()
+            .map_err(|err| Error::Spawn {
+                program: command.get_program().to_string_lossy().to_string(),
+                reason: err.to_string(),
+            })
+            .and_then(|output| if output.status.success() {
+                Ok(output)
+            } else {
+                Err(Error::Tarsnap {
+                    exit_code: output.status.code(),
+                    stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+                })
+            })
+    }
+
+    // The options that are given to every tarsnap invocation
+    fn common_args(&self, keyfile: &Option<PathBuf>) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        let path_options = [
+            ("--keyfile", keyfile),
+            ("--cachedir", &self.cachedir),
+            ("--configfile", &self.configfile),
+        ];
+        for &(option, value) in &path_options {
+            if let Some(path) = value {
+                args.push(option.into());
+                args.push(path.into());
+            }
+        }
+        args.extend(self.extra_args.iter().map(OsString::from));
+        args
+    }
+}
+
+impl ArchiveBackend for Tarsnap {
+    fn list_archives(&self) -> Result<Vec<Snapshot>, Error> {
+        // A newline listing can't be trusted with strict names, because a
+        // name containing a newline looks like several archives
+        if self.strict_names && !self.null_output {
+            return Err(Error::invalid_argument(
+                "--strict-names",
+                "can't be used without --null-output",
+            ));
+        }
+
+        let snapshots = if self.null_output {
+            // tarsnap versions before 1.0.39 don't know --null-output
+            match self.list_archives_output(true) {
+                Err(Error::Tarsnap { ref stderr, .. }) if stderr.contains("--null-output") => {
+                    if self.strict_names {
+                        return Err(Error::invalid_argument(
+                            "--strict-names",
+                            "tarsnap doesn't support --null-output",
+                        ));
+                    }
+                    warn!(
+                        "tarsnap doesn't support --null-output. Archive names containing \
+                         newlines can't be listed"
+                    );
+                    self.list_archives_output(false).and_then(parse_archives)
+                }
+                res => res.and_then(parse_null_archives),
+            }
+        } else {
+            self.list_archives_output(false).and_then(parse_archives)
+        }?;
+
+        if self.strict_names {
+            check_archive_names(&snapshots)?;
+        }
+
+        Ok(snapshots)
+    }
+
+    fn delete_archives(&mut self, names: &[String]) -> Result<(), Error> {
+        let keyfile = self.delete_keyfile.clone().or_else(|| self.keyfile.clone());
+        let mut args = self.common_args(&keyfile);
+        args.push("-d".into());
+        for name in names {
+            args.push("-f".into());
+            args.push(name.into());
+        }
+        self.run(&mut self.command(&args)).map(|_| ())
+    }
+
+    // Run "tarsnap --print-stats" for all archives and for the named
+    // archives
+    fn print_stats(&self, names: &[String]) -> Result<StorageStats, Error> {
+        let mut args = self.common_args(&self.keyfile);
+        args.push("--print-stats".into());
+        args.push("--no-humanize-numbers".into());
+        for name in names {
+            args.push("-f".into());
+            args.push(name.into());
+        }
+        self.run(&mut self.command(&args))
+            .and_then(|output| parse_stats(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+// Find a binary given without a directory in PATH. As in execvp, files
+// that are not executable are passed over. Other paths and binaries that
+// are not found are returned as is.
+fn resolve_binary(binary: &Path) -> PathBuf {
+    if binary.components().count() != 1 {
+        return binary.to_path_buf();
+    }
+
+    env::var_os("PATH")
+        .and_then(|paths| search_path(binary, &paths))
+        .unwrap_or_else(|| binary.to_path_buf())
+}
+
+fn search_path(binary: &Path, paths: &OsStr) -> Option<PathBuf> {
+    env::split_paths(paths)
+        .map(|dir| dir.join(binary))
+        .find(|path| is_executable(path))
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+// Quote a word for logging so that the command line can be pasted into a
+// shell
+fn shell_quote(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn no_options() {
+        let tarsnap = Tarsnap::new();
+        assert_eq!(tarsnap.common_args(&tarsnap.keyfile), Vec::<OsString>::new());
+    }
+
+    #[test]
+    fn all_options() {
+        let tarsnap = Tarsnap {
+            binary: PathBuf::from("/usr/local/bin/tarsnap"),
+            prefix: Vec::new(),
+            keyfile: Some(PathBuf::from("/root/read.key")),
+            delete_keyfile: Some(PathBuf::from("/root/delete.key")),
+            cachedir: Some(PathBuf::from("/var/cache/tarsnap")),
+            configfile: Some(PathBuf::from("/etc/tarsnap.conf")),
+            extra_args: vec!["--humanize-numbers".to_string()],
+            null_output: true,
+            strict_names: false,
+        };
+        let expected: Vec<OsString> = vec![
+            "--keyfile",
+            "/root/delete.key",
+            "--cachedir",
+            "/var/cache/tarsnap",
+            "--configfile",
+            "/etc/tarsnap.conf",
+            "--humanize-numbers",
+        ].into_iter()
+            .map(OsString::from)
+            .collect();
+
+        assert_eq!(tarsnap.common_args(&tarsnap.delete_keyfile), expected);
+    }
+
+    #[test]
+    fn prefixed_command_line() {
+        let tarsnap = Tarsnap {
+            binary: PathBuf::from("/opt/tarsnap/bin/tarsnap"),
+            prefix: vec!["sudo".to_string(), "-u".to_string(), "backup".to_string()],
+            ..Tarsnap::new()
+        };
+        let expected: Vec<OsString> = vec![
+            "sudo",
+            "-u",
+            "backup",
+            "/opt/tarsnap/bin/tarsnap",
+            "--list-archives",
+        ].into_iter()
+            .map(OsString::from)
+            .collect();
+
+        assert_eq!(tarsnap.command_line(&["--list-archives".into()]), expected);
+    }
+
+    #[test]
+    fn executable_in_path() {
+        let first = TempDir::new().unwrap();
+        let second = TempDir::new().unwrap();
+        let not_executable = first.path().join("tarsnap");
+        let executable = second.path().join("tarsnap");
+        fs::write(&not_executable, "").unwrap();
+        fs::write(&executable, "").unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+        let paths = env::join_paths([first.path(), second.path()]).unwrap();
+
+        assert_eq!(search_path(Path::new("tarsnap"), &paths), Some(executable));
+        assert_eq!(search_path(Path::new("missing"), &paths), None);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(shell_quote("--keyfile"), "--keyfile");
+        assert_eq!(shell_quote("my archive"), "'my archive'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+}
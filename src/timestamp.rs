@@ -0,0 +1,423 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+use std::str::FromStr;
+use regex::{self, Captures, Regex};
+use chrono::prelude::*;
+use chrono::{Duration, LocalResult, NaiveDateTime};
+use chrono::format::{Item, Numeric, StrftimeItems};
+use chrono_tz::Tz;
+use error::Error;
+use snapshot::Snapshot;
+
+/// Extracts snapshot timestamps from archive names.
+///
+/// The format is either a strftime-style format, such as
+/// `%Y-%m-%d_%H-%M-%S`, or a regular expression with the named groups
+/// `year`, `month` and `day` and optionally `hour`, `minute` and `second`.
+/// The format may match anywhere in the name. The timestamps are in UTC
+/// unless another time zone is set with `with_timezone`. `%s`, the
+/// seconds since the epoch, doesn't depend on the time zone.
+#[derive(Clone, Debug)]
+pub struct NameTimestamp {
+    re: Regex,
+    strftime: Option<String>,
+    timezone: Tz,
+}
+
+impl FromStr for NameTimestamp {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<NameTimestamp, Error> {
+        // A named group, (?P<name>...) or (?<name>...), makes it a regex
+        if Regex::new(r"\(\?P?<\w").unwrap().is_match(s) {
+            let re = Regex::new(s).map_err(|err| Error::invalid_argument(s, err))?;
+            for group in &["year", "month", "day"] {
+                if !re.capture_names().any(|name| name == Some(group)) {
+                    let reason = format!("the timestamp regex must have a group named {}", group);
+                    return Err(Error::invalid_argument(s, reason));
+                }
+            }
+            Ok(NameTimestamp {
+                re,
+                strftime: None,
+                timezone: Tz::UTC,
+            })
+        } else {
+            let re = strftime_to_regex(s)
+                .and_then(|re| Regex::new(&re).map_err(|err| err.to_string()))
+                .map_err(|err| Error::invalid_argument(s, err))?;
+            Ok(NameTimestamp {
+                re,
+                strftime: Some(s.to_string()),
+                timezone: Tz::UTC,
+            })
+        }
+    }
+}
+
+impl NameTimestamp {
+    /// Read the timestamps as local times in `timezone`.
+    pub fn with_timezone(self, timezone: Tz) -> NameTimestamp {
+        NameTimestamp { timezone, ..self }
+    }
+
+    /// Parse the timestamp from an archive name. Returns `None` if the
+    /// name doesn't match the format.
+    pub fn parse(&self, name: &str) -> Option<DateTime<Utc>> {
+        let caps = self.re.captures(name)?;
+        let naive = match self.strftime {
+            Some(ref format) => parse_naive(&caps[0], format),
+            None => naive_from_captures(&caps),
+        };
+        // The seconds since the epoch are the same in every time zone
+        let timezone = match self.strftime {
+            Some(ref format) if has_epoch_seconds(format) => Tz::UTC,
+            _ => self.timezone,
+        };
+
+        naive.map(|t| from_local(&timezone, &t))
+    }
+}
+
+/// What to do with archives whose name doesn't contain a timestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampFallback {
+    /// Abort.
+    Error,
+    /// Leave the archive out of the rotation. It is never deleted.
+    Skip,
+    /// Use the creation time reported by the archive backend.
+    CreationTime,
+}
+
+impl FromStr for TimestampFallback {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TimestampFallback, Error> {
+        match s {
+            "error" => Ok(TimestampFallback::Error),
+            "skip" => Ok(TimestampFallback::Skip),
+            "creation-time" => Ok(TimestampFallback::CreationTime),
+            _ => Err(Error::invalid_argument(s, "unknown timestamp fallback")),
+        }
+    }
+}
+
+/// Replace the creation times of snapshots by the timestamps in their
+/// names.
+///
+/// Returns the snapshots to rotate and, separately, the snapshots that
+/// were skipped by `TimestampFallback::Skip`. The skipped snapshots keep
+/// their creation times.
+pub fn apply_name_timestamps(
+    snapshots: Vec<Snapshot>,
+    name_timestamp: &NameTimestamp,
+    fallback: TimestampFallback,
+) -> Result<(Vec<Snapshot>, Vec<Snapshot>), Error> {
+    let mut res = Vec::new();
+    let mut skipped = Vec::new();
+    for snapshot in snapshots {
+        match (name_timestamp.parse(&snapshot.name), fallback) {
+            (Some(ts), _) => res.push(Snapshot { ts, ..snapshot }),
+            (None, TimestampFallback::Error) => {
+                return Err(Error::Parse {
+                    row: snapshot.name,
+                    reason: "no timestamp in the archive name".to_string(),
+                })
+            }
+            (None, TimestampFallback::Skip) => {
+                info!("No timestamp in archive name {}, skipping it", snapshot.name);
+                skipped.push(snapshot);
+            }
+            (None, TimestampFallback::CreationTime) => {
+                debug!("No timestamp in archive name {}, using creation time", snapshot.name);
+                res.push(snapshot);
+            }
+        }
+    }
+
+    Ok((res, skipped))
+}
+
+/// A timestamp given on the command line. A timestamp without an offset
+/// is a local time in the time zone of the run, which is known only after
+/// the configuration file has been read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timestamp {
+    Utc(DateTime<Utc>),
+    Local(NaiveDateTime),
+}
+
+impl Timestamp {
+    /// The timestamp with a local time read in `timezone`, see
+    /// `from_local`.
+    pub fn in_timezone(&self, timezone: &Tz) -> DateTime<Utc> {
+        match *self {
+            Timestamp::Utc(t) => t,
+            Timestamp::Local(ref t) => from_local(timezone, t),
+        }
+    }
+}
+
+/// Parse a timestamp given on the command line: RFC 3339, such as
+/// "2018-07-16T11:01:03+03:00", or the local time "2018-07-16 11:01:03"
+/// or "2018-07-16".
+pub fn parse_timestamp(s: &str) -> Result<Timestamp, Error> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| Timestamp::Utc(t.with_timezone(&Utc)))
+        .ok()
+        .or_else(|| {
+            parse_naive(s, "%Y-%m-%d %H:%M:%S")
+                .or_else(|| parse_naive(s, "%Y-%m-%d"))
+                .map(Timestamp::Local)
+        })
+        .ok_or_else(|| {
+            Error::invalid_argument(s, "expected a timestamp such as 2018-07-16 11:01:03")
+        })
+}
+
+/// Convert a local time in `timezone` to UTC. A local time that is
+/// skipped by a daylight saving time change moves an hour forward, and a
+/// repeated local time is the first of the two.
+pub fn from_local(timezone: &Tz, t: &NaiveDateTime) -> DateTime<Utc> {
+    match timezone.from_local_datetime(t) {
+        LocalResult::Single(local) | LocalResult::Ambiguous(local, _) => local.with_timezone(&Utc),
+        LocalResult::None => from_local(timezone, &(*t + Duration::hours(1))),
+    }
+}
+
+/// Parse an IANA time zone name, such as "Europe/Helsinki" or "UTC".
+pub fn parse_timezone(s: &str) -> Result<Tz, Error> {
+    s.parse::<Tz>().map_err(|_| {
+        Error::invalid_argument(s, "expected a time zone name such as Europe/Helsinki")
+    })
+}
+
+// Translate a strftime format into a regular expression that matches the
+// formatted timestamps
+fn strftime_to_regex(format: &str) -> Result<String, String> {
+    let mut re = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            re.push_str(&regex::escape(&c.to_string()));
+            continue;
+        }
+
+        match chars.next() {
+            Some('Y') => re.push_str(r"\d{4}"),
+            Some('m') | Some('d') | Some('H') | Some('M') | Some('S') | Some('y') => {
+                re.push_str(r"\d{2}")
+            }
+            Some('j') => re.push_str(r"\d{3}"),
+            Some('s') => re.push_str(r"\d+"),
+            Some('%') => re.push('%'),
+            Some(x) => return Err(format!("unsupported format specifier %{}", x)),
+            None => return Err("the format ends with %".to_string()),
+        }
+    }
+
+    Ok(re)
+}
+
+// Does a strftime format contain %s? An escaped %%s is a literal percent
+// sign followed by "s".
+fn has_epoch_seconds(format: &str) -> bool {
+    StrftimeItems::new(format).any(|item| matches!(item, Item::Numeric(Numeric::Timestamp, _)))
+}
+
+// Formats without the time of day are parsed as midnight
+fn parse_naive(s: &str, format: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(s, format)
+        .ok()
+        .or_else(|| NaiveDate::parse_from_str(s, format).ok().map(|d| d.and_hms(0, 0, 0)))
+}
+
+fn naive_from_captures(caps: &Captures) -> Option<NaiveDateTime> {
+    let field = |name: &str| -> Option<u32> {
+        match caps.name(name) {
+            Some(m) => m.as_str().parse().ok(),
+            None => Some(0),
+        }
+    };
+
+    let year = caps.name("year")?.as_str().parse().ok()?;
+    NaiveDate::from_ymd_opt(year, field("month")?, field("day")?)?
+        .and_hms_opt(field("hour")?, field("minute")?, field("second")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(name: &str) -> Snapshot {
+        Snapshot {
+            name: name.to_string(),
+            ts: Utc.ymd(2019, 1, 1).and_hms(0, 0, 0),
+        }
+    }
+
+    #[test]
+    fn strftime_format() {
+        let parser: NameTimestamp = "%Y-%m-%d_%H-%M-%S".parse().unwrap();
+        assert_eq!(
+            parser.parse("archive-2018-07-16_11-01-03"),
+            Some(Utc.ymd(2018, 7, 16).and_hms(11, 1, 3))
+        );
+        assert_eq!(parser.parse("archive-2018-07-16"), None);
+        assert_eq!(parser.parse("archive-2018-13-16_11-01-03"), None);
+    }
+
+    #[test]
+    fn strftime_date_only() {
+        let parser: NameTimestamp = "%Y%m%d".parse().unwrap();
+        assert_eq!(parser.parse("etc-20180716"), Some(Utc.ymd(2018, 7, 16).and_hms(0, 0, 0)));
+    }
+
+    #[test]
+    fn unsupported_strftime_specifier() {
+        assert!("%Y-%b".parse::<NameTimestamp>().is_err());
+    }
+
+    #[test]
+    fn regex_format() {
+        let parser: NameTimestamp =
+            r"(?P<day>\d\d)\.(?P<month>\d\d)\.(?P<year>\d{4})(-(?P<hour>\d\d))?"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            parser.parse("home-16.07.2018-11"),
+            Some(Utc.ymd(2018, 7, 16).and_hms(11, 0, 0))
+        );
+        assert_eq!(
+            parser.parse("home-16.07.2018"),
+            Some(Utc.ymd(2018, 7, 16).and_hms(0, 0, 0))
+        );
+        assert_eq!(parser.parse("home-latest"), None);
+    }
+
+    #[test]
+    fn regex_format_without_p() {
+        let parser: NameTimestamp = r"(?<year>\d{4})(?<month>\d\d)(?<day>\d\d)".parse().unwrap();
+        assert_eq!(parser.parse("etc-20180716"), Some(Utc.ymd(2018, 7, 16).and_hms(0, 0, 0)));
+    }
+
+    #[test]
+    fn regex_without_required_groups() {
+        assert!(r"(?P<year>\d{4})-(?P<month>\d\d)".parse::<NameTimestamp>().is_err());
+    }
+
+    #[test]
+    fn local_name_timestamps() {
+        let parser = "%Y-%m-%d_%H-%M-%S"
+            .parse::<NameTimestamp>()
+            .unwrap()
+            .with_timezone(Tz::Europe__Helsinki);
+        assert_eq!(
+            parser.parse("archive-2018-07-16_11-01-03"),
+            Some(Utc.ymd(2018, 7, 16).and_hms(8, 1, 3))
+        );
+        // The hour skipped in spring moves forward
+        assert_eq!(
+            parser.parse("archive-2018-03-25_03-30-00"),
+            Some(Utc.ymd(2018, 3, 25).and_hms(1, 30, 0))
+        );
+
+        let parser = "%s".parse::<NameTimestamp>().unwrap().with_timezone(Tz::Europe__Helsinki);
+        assert_eq!(parser.parse("etc-1531738863"), Some(Utc.ymd(2018, 7, 16).and_hms(11, 1, 3)));
+
+        // %%s is a literal "%s", not the seconds since the epoch
+        let parser = "%%s-%Y%m%d"
+            .parse::<NameTimestamp>()
+            .unwrap()
+            .with_timezone(Tz::Europe__Helsinki);
+        assert_eq!(parser.parse("etc-%s-20180716"), Some(Utc.ymd(2018, 7, 15).and_hms(21, 0, 0)));
+    }
+
+    #[test]
+    fn command_line_timestamps() {
+        let helsinki = Tz::Europe__Helsinki;
+        let parse = |s: &str, timezone: &Tz| parse_timestamp(s).map(|t| t.in_timezone(timezone));
+        assert_eq!(
+            parse("2018-07-16T11:01:03+03:00", &Tz::UTC),
+            Ok(Utc.ymd(2018, 7, 16).and_hms(8, 1, 3))
+        );
+        assert_eq!(
+            parse("2018-07-16T11:01:03Z", &helsinki),
+            Ok(Utc.ymd(2018, 7, 16).and_hms(11, 1, 3))
+        );
+        assert_eq!(
+            parse("2018-07-16 11:01:03", &Tz::UTC),
+            Ok(Utc.ymd(2018, 7, 16).and_hms(11, 1, 3))
+        );
+        assert_eq!(
+            parse("2018-07-16 11:01:03", &helsinki),
+            Ok(Utc.ymd(2018, 7, 16).and_hms(8, 1, 3))
+        );
+        assert_eq!(parse("2018-07-16", &helsinki), Ok(Utc.ymd(2018, 7, 15).and_hms(21, 0, 0)));
+        assert!(parse_timestamp("16.7.2018").is_err());
+    }
+
+    #[test]
+    fn fallback_policies() {
+        let parser: NameTimestamp = "%Y-%m-%d".parse().unwrap();
+        let snapshots = vec![snapshot("etc-2018-07-16"), snapshot("manual")];
+
+        assert!(apply_name_timestamps(snapshots.clone(), &parser, TimestampFallback::Error)
+            .is_err());
+        assert_eq!(
+            apply_name_timestamps(snapshots.clone(), &parser, TimestampFallback::Skip),
+            Ok((
+                vec![Snapshot {
+                    name: "etc-2018-07-16".to_string(),
+                    ts: Utc.ymd(2018, 7, 16).and_hms(0, 0, 0),
+                }],
+                vec![snapshot("manual")],
+            ))
+        );
+        assert_eq!(
+            apply_name_timestamps(snapshots, &parser, TimestampFallback::CreationTime),
+            Ok((
+                vec![
+                    Snapshot {
+                        name: "etc-2018-07-16".to_string(),
+                        ts: Utc.ymd(2018, 7, 16).and_hms(0, 0, 0),
+                    },
+                    snapshot("manual"),
+                ],
+                Vec::new(),
+            ))
+        );
+    }
+
+    #[test]
+    fn timezones() {
+        assert_eq!(parse_timezone("Europe/Helsinki"), Ok(Tz::Europe__Helsinki));
+        assert_eq!(parse_timezone("UTC"), Ok(Tz::UTC));
+        assert!(parse_timezone("Mars/Olympus_Mons").is_err());
+    }
+}
@@ -0,0 +1,912 @@
+/*
+tarsnap-lifespan
+Copyright 2018 Antti Ajanki <antti.ajanki@iki.fi>
+
+The MIT License(MIT)
+
+Permission is hereby granted, free of charge, to any person obtaining
+a copy of this software and associated documentation files (the
+"Software"), to deal in the Software without restriction, including
+without limitation the rights to use, copy, modify, merge, publish,
+distribute, sublicense, and/or sell copies of the Software, and to
+permit persons to whom the Software is furnished to do so, subject to
+the following conditions:
+
+The above copyright notice and this permission notice shall be
+included in all copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT,
+TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION WITH THE
+SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+*/
+
+// End-to-end tests of the command line tool against the fake tarsnap in
+// tests/mock-tarsnap.
+
+extern crate chrono;
+extern crate serde_json;
+extern crate tarsnap_lifespan;
+extern crate tempfile;
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::{thread, time};
+use chrono::prelude::*;
+use chrono::Duration;
+use tarsnap_lifespan::Lock;
+use tempfile::TempDir;
+
+struct MockTarsnap {
+    dir: TempDir,
+    env: Vec<(String, String)>,
+}
+
+impl MockTarsnap {
+    // archives are (name, hours ago) pairs
+    fn new(archives: &[(&str, i64)]) -> MockTarsnap {
+        let mock = MockTarsnap {
+            dir: TempDir::new().unwrap(),
+            env: Vec::new(),
+        };
+        let now = Utc::now();
+        let state: String = archives
+            .iter()
+            .map(|&(name, hours)| {
+                let ts = now - Duration::hours(hours);
+                format!("{}\t{}\n", name, ts.format("%Y-%m-%d %H:%M:%S"))
+            })
+            .collect();
+        fs::write(mock.state_path(), state).unwrap();
+
+        mock
+    }
+
+    fn env(mut self, key: &str, value: &str) -> MockTarsnap {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.dir.path().join("state")
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.dir.path().join("log")
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        self.dir.path().join("lock")
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        let lock_file = self.lock_path();
+        let mut with_lock_file = vec!["--lock-file", lock_file.to_str().unwrap()];
+        with_lock_file.extend_from_slice(args);
+        self.run_with_default_lock(&with_lock_file)
+    }
+
+    // Run without --lock-file
+    fn run_with_default_lock(&self, args: &[&str]) -> Output {
+        let binary = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/mock-tarsnap");
+        let mut command = Command::new(env!("CARGO_BIN_EXE_tarsnap-lifespan"));
+        command
+            .args(args)
+            .env("TARSNAP_LIFESPAN_TARSNAP", binary)
+            .env("MOCK_TARSNAP_STATE", self.state_path())
+            .env("MOCK_TARSNAP_LOG", self.log_path());
+        for (key, value) in &self.env {
+            command.env(key, value);
+        }
+
+        command.output().unwrap()
+    }
+
+    fn archives(&self) -> Vec<String> {
+        fs::read_to_string(self.state_path())
+            .unwrap()
+            .lines()
+            .map(|line| line.split('\t').next().unwrap().to_string())
+            .collect()
+    }
+
+    fn log(&self) -> Vec<String> {
+        fs::read_to_string(self.log_path())
+            .unwrap_or_default()
+            .lines()
+            .map(|x| x.to_string())
+            .collect()
+    }
+}
+
+fn daily_archives() -> MockTarsnap {
+    MockTarsnap::new(&[("old-1", 240), ("old-2", 216), ("daily", 25), ("latest", 1)])
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).to_string()
+}
+
+fn json_report(output: &Output) -> serde_json::Value {
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn deletes_expired_archives() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+    assert_eq!(
+        mock.log(),
+        vec![
+            "--list-archives -v --null-output",
+            "-d -f old-1 -f old-2",
+            "--list-archives -v --null-output",
+        ]
+    );
+}
+
+#[test]
+fn dry_run_deletes_nothing() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--dry-run", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["old-1", "old-2", "daily", "latest"]);
+    assert_eq!(mock.log(), vec!["--list-archives -v --null-output"]);
+}
+
+#[test]
+fn delete_keyfile_is_used_for_deleting() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--keyfile", "read.key", "--delete-keyfile", "delete.key", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        mock.log(),
+        vec![
+            "--keyfile read.key --list-archives -v --null-output",
+            "--keyfile delete.key -d -f old-1 -f old-2",
+            "--keyfile read.key --list-archives -v --null-output",
+        ]
+    );
+}
+
+#[test]
+fn json_report_lists_all_archives() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--format", "json", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report = json_report(&output);
+    let actions: Vec<(&str, &str)> = report["snapshots"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| (x["name"].as_str().unwrap(), x["action"].as_str().unwrap()))
+        .collect();
+    assert_eq!(
+        actions,
+        vec![
+            ("old-1", "delete"),
+            ("old-2", "delete"),
+            ("daily", "keep"),
+            ("latest", "keep"),
+        ]
+    );
+    assert_eq!(report["deletion"]["status"], "deleted");
+}
+
+#[test]
+fn listing_failure() {
+    let mock = daily_archives().env("MOCK_TARSNAP_FAIL_LIST", "1");
+
+    let output = mock.run(&["1D"]);
+
+    assert_eq!(output.status.code(), Some(5));
+    assert!(stderr(&output).contains("Error connecting to the server"));
+    assert_eq!(mock.archives().len(), 4);
+}
+
+#[test]
+fn garbage_listing() {
+    let mock = daily_archives().env("MOCK_TARSNAP_GARBAGE", "1");
+
+    let output = mock.run(&["1D"]);
+
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(mock.archives().len(), 4);
+}
+
+#[test]
+fn partial_delete() {
+    let mock = daily_archives().env("MOCK_TARSNAP_FAIL_DELETE", "1");
+
+    let output = mock.run(&["--format", "json", "1D"]);
+
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(mock.archives(), vec!["old-2", "daily", "latest"]);
+    let report = json_report(&output);
+    assert_eq!(report["deletion"]["status"], "failed");
+    assert_eq!(report["deletion"]["exit_code"], 5);
+    assert_eq!(report["snapshots"][0]["status"], "deleted");
+    assert_eq!(report["snapshots"][1]["status"], "not-deleted");
+    assert!(report["snapshots"][2].get("status").is_none());
+
+    // A later run deletes the rest
+    let mock = mock.env("MOCK_TARSNAP_FAIL_DELETE", "");
+    let output = mock.run(&["1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn batched_delete() {
+    let mock = MockTarsnap::new(&[
+        ("old-1", 240),
+        ("old-2", 216),
+        ("old-3", 192),
+        ("latest", 1),
+    ]).env("MOCK_TARSNAP_FAIL_DELETE", "2");
+
+    let output = mock.run(&["--batch-size", "2", "--format", "json", "1D"]);
+
+    assert_eq!(output.status.code(), Some(5));
+    assert_eq!(mock.archives(), vec!["old-3", "latest"]);
+    assert_eq!(
+        mock.log(),
+        vec![
+            "--list-archives -v --null-output",
+            "-d -f old-1 -f old-2",
+            "-d -f old-3",
+            "--list-archives -v --null-output",
+        ]
+    );
+    let report = json_report(&output);
+    let statuses: Vec<&str> = report["snapshots"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .take(3)
+        .map(|x| x["status"].as_str().unwrap())
+        .collect();
+    assert_eq!(statuses, vec!["deleted", "deleted", "not-deleted"]);
+}
+
+#[test]
+fn missing_tarsnap_binary() {
+    let mock = daily_archives().env("TARSNAP_LIFESPAN_TARSNAP", "/nonexistent/tarsnap");
+
+    let output = mock.run(&["1D"]);
+
+    assert_eq!(output.status.code(), Some(4));
+}
+
+#[test]
+fn invalid_generation() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["1X"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(mock.log().is_empty());
+}
+
+#[test]
+fn too_long_duration() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--min-age", "99999999999999999d", "1D"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("too long"), "{}", stderr(&output));
+    assert!(mock.log().is_empty());
+}
+
+#[test]
+fn explain_deletes_nothing() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["explain", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Deleted:\n  old-1\n  old-2\n"), "{}", stdout);
+    assert_eq!(mock.archives().len(), 4);
+}
+
+#[test]
+fn tarsnap_path_and_prefix() {
+    // The prefix unsets the failure injection
+    let mock = daily_archives()
+        .env("TARSNAP_LIFESPAN_TARSNAP", "/nonexistent/tarsnap")
+        .env("MOCK_TARSNAP_FAIL_LIST", "1");
+    let binary = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/mock-tarsnap");
+
+    let output = mock.run(&[
+        "--tarsnap-path",
+        binary.to_str().unwrap(),
+        "--tarsnap-prefix",
+        "env",
+        "--tarsnap-prefix",
+        "-u",
+        "--tarsnap-prefix",
+        "MOCK_TARSNAP_FAIL_LIST",
+        "1D",
+    ]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn tarsnap_path_in_config_overrides_environment() {
+    let mock = daily_archives().env("TARSNAP_LIFESPAN_TARSNAP", "/nonexistent/tarsnap");
+    let binary = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/mock-tarsnap");
+    let config = mock.dir.path().join("config.toml");
+    fs::write(
+        &config,
+        format!("[policy.default]\ntarsnap-path = {:?}\n", binary.to_str().unwrap()),
+    ).unwrap();
+
+    let output = mock.run(&["--config", config.to_str().unwrap(), "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn command_line_switches_off_config_flags() {
+    let mock = daily_archives();
+    let config = mock.dir.path().join("config.toml");
+    fs::write(&config, "[policy.default]\ndry-run = true\n").unwrap();
+    let config = config.to_str().unwrap();
+
+    let output = mock.run(&["--config", config, "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives().len(), 4);
+
+    let output = mock.run(&["--config", config, "--dry-run", "--no-dry-run", "1D"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(mock.archives().len(), 4);
+
+    let output = mock.run(&["--config", config, "--no-dry-run", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn missing_prefix_command() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--tarsnap-prefix", "/nonexistent/nice", "1D"]);
+
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stderr(&output).contains("/nonexistent/nice"), "{}", stderr(&output));
+}
+
+#[test]
+fn delete_limit() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--max-delete", "1", "--format", "json", "1D"]);
+
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(mock.archives().len(), 4);
+    assert_eq!(json_report(&output)["deletion"]["exit_code"], 6);
+
+    let output = mock.run(&["--max-delete-percent", "25", "1D"]);
+
+    assert_eq!(output.status.code(), Some(6));
+    assert_eq!(mock.archives().len(), 4);
+}
+
+#[test]
+fn force_overrides_delete_limit() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--max-delete", "1", "--force", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn keep_last_and_min_age() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--keep-last", "3", "--min-age", "11d", "--format", "json", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["old-1", "old-2", "daily", "latest"]);
+    let report = json_report(&output);
+    assert_eq!(report["snapshots"][0]["reasons"][0]["rule"], "min-age");
+    assert_eq!(report["snapshots"][1]["reasons"][0]["rule"], "keep-last");
+    assert_eq!(report["snapshots"][1]["reasons"][0]["position"], 3);
+}
+
+#[test]
+fn protected_archives() {
+    let mock = daily_archives();
+    let protect_file = mock.dir.path().join("protected");
+    fs::write(&protect_file, "# kept for the audit\nold-2\n").unwrap();
+
+    let output = mock.run(&[
+        "--protect",
+        "^old-1$",
+        "--protect-file",
+        protect_file.to_str().unwrap(),
+        "1D",
+    ]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["old-1", "old-2", "daily", "latest"]);
+}
+
+#[test]
+fn holds() {
+    let mock = daily_archives();
+    let holds = mock.dir.path().join("holds.toml");
+    let holds = holds.to_str().unwrap();
+
+    // Only hold add creates a missing holds file
+    for args in &[vec!["1D"], vec!["hold", "list"], vec!["hold", "remove", "old-1"]] {
+        let output = mock.run(&[&["--holds", holds][..], args].concat());
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(stderr(&output).contains("holds.toml"), "{}", stderr(&output));
+    }
+    assert_eq!(mock.archives(), vec!["old-1", "old-2", "daily", "latest"]);
+
+    let output = mock.run(&["--holds", holds, "hold", "add", "old-1", "--reason", "incident 42"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = mock.run(&["--holds", holds, "hold", "add", "old-2", "--expires", "2018-01-01"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = mock.run(&["--holds", holds, "hold", "list"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "old-1\tnever expires\tincident 42\nold-2\texpired 2018-01-01 00:00:00\n"
+    );
+
+    let output = mock.run(&["--holds", holds, "--format", "json", "1D"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["old-1", "daily", "latest"]);
+    let report = json_report(&output);
+    assert_eq!(report["snapshots"][0]["reasons"][0]["rule"], "hold");
+    assert_eq!(report["snapshots"][0]["reasons"][0]["reason"], "incident 42");
+    assert_eq!(report["expired_holds"][0]["archive"], "old-2");
+
+    let output = mock.run(&["--holds", holds, "hold", "remove", "old-1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = mock.run(&["--holds", holds, "hold", "remove", "old-1"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn future_archive_is_clock_skew() {
+    let mock = MockTarsnap::new(&[("old", 240), ("future", -48)]);
+
+    let output = mock.run(&["1D"]);
+
+    assert_eq!(output.status.code(), Some(7));
+    assert!(stderr(&output).contains("in the future"), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["old", "future"]);
+}
+
+#[test]
+fn now_option() {
+    let mock = daily_archives();
+    let later = (Utc::now() + Duration::days(30)).format("%Y-%m-%d %H:%M:%S").to_string();
+
+    let output = mock.run(&["--now", &later, "--max-newest-age", "2d", "1D"]);
+
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(mock.archives().len(), 4);
+
+    let earlier = (Utc::now() - Duration::hours(2)).format("%Y-%m-%d %H:%M:%S").to_string();
+    let output = mock.run(&["--now", &earlier, "--max-future", "2h", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn old_tarsnap_without_null_output() {
+    let mock = daily_archives().env("MOCK_TARSNAP_OLD", "1");
+
+    let output = mock.run(&["1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("doesn't support --null-output"));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+    assert_eq!(
+        mock.log(),
+        vec![
+            "--list-archives -v --null-output",
+            "--list-archives -v",
+            "-d -f old-1 -f old-2",
+            "--list-archives -v --null-output",
+            "--list-archives -v",
+        ]
+    );
+}
+
+#[test]
+fn strict_names_require_null_output() {
+    // Without --null-output, the archive "x\t2018-01-01 00:00:00\nimportant"
+    // is listed exactly like these two archives
+    let mock = MockTarsnap::new(&[("x", 240), ("important", 2), ("latest", 1)])
+        .env("MOCK_TARSNAP_OLD", "1");
+
+    let output = mock.run(&["--strict-names", "1D"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("--null-output"), "{}", stderr(&output));
+    assert_eq!(mock.log(), vec!["--list-archives -v --null-output"]);
+
+    let output = mock.run(&["--strict-names", "--no-null-output", "1D"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(mock.archives(), vec!["x", "important", "latest"]);
+}
+
+#[test]
+fn timezone_sets_day_boundaries() {
+    // In Brisbane (UTC+10), all three archives are from the same day. The
+    // host time zone must not affect how the listing is parsed.
+    let mock = || {
+        let mock = MockTarsnap::new(&[]).env("TZ", "America/New_York");
+        let state = "evening\t2018-07-19 20:00:00\n\
+                     morning\t2018-07-20 02:00:00\n\
+                     latest\t2018-07-20 12:00:00\n";
+        fs::write(mock.state_path(), state).unwrap();
+        mock
+    };
+    let args = ["--now", "2018-07-20T13:00:00Z", "--strategy", "bucket-oldest", "2D"];
+
+    let utc = mock();
+    let output = utc.run(&args);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(utc.archives(), vec!["evening", "morning", "latest"]);
+
+    let brisbane = mock();
+    let output = brisbane.run(&[&["--timezone", "Australia/Brisbane"], &args[..]].concat());
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(brisbane.archives(), vec!["evening", "latest"]);
+
+    let output = brisbane.run(&["--timezone", "Mars/Olympus_Mons", "2D"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("time zone"), "{}", stderr(&output));
+}
+
+#[test]
+fn timezone_applies_to_name_timestamps() {
+    // Read as UTC, the newest name would be 2.5 hours in the future
+    let mock = MockTarsnap::new(&[
+        ("db-2018-07-18_23-30-00", 48),
+        ("db-2018-07-19_01-00-00", 48),
+        ("db-2018-07-20_11-30-00", 1),
+    ]);
+
+    let output = mock.run(&[
+        "--timezone",
+        "Europe/Helsinki",
+        "--now",
+        "2018-07-20 12:00:00",
+        "--timestamp-from-name",
+        "%Y-%m-%d_%H-%M-%S",
+        "--strategy",
+        "bucket-oldest",
+        "2D",
+    ]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["db-2018-07-19_01-00-00", "db-2018-07-20_11-30-00"]);
+}
+
+#[test]
+fn skipped_archives_are_reported() {
+    let mock = MockTarsnap::new(&[
+        ("db-2018-07-18_00-00-00", 60),
+        ("db-2018-07-19_01-00-00", 36),
+        ("db-2018-07-20_11-30-00", 1),
+        ("manual-1", 10),
+        ("manual-2", 5),
+    ]);
+
+    // One of the five archives is deleted, which is below the limit even
+    // though it is a third of the archives with a timestamp
+    let output = mock.run(&[
+        "--now",
+        "2018-07-20 12:00:00",
+        "--timestamp-from-name",
+        "%Y-%m-%d_%H-%M-%S",
+        "--timestamp-fallback",
+        "skip",
+        "--max-delete-percent",
+        "25",
+        "--format",
+        "json",
+        "1D",
+    ]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        mock.archives(),
+        vec!["db-2018-07-19_01-00-00", "db-2018-07-20_11-30-00", "manual-1", "manual-2"]
+    );
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["summary"]["total"], 5);
+    let manual = report["snapshots"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["name"] == "manual-1")
+        .unwrap();
+    assert_eq!(manual["action"], "keep");
+    assert_eq!(manual["reasons"][0]["rule"], "no-timestamp");
+}
+
+#[test]
+fn print_stats() {
+    let mock = daily_archives();
+
+    let output = mock.run(&["--print-stats", "--format", "json", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        mock.log(),
+        vec![
+            "--list-archives -v --null-output",
+            "--print-stats --no-humanize-numbers -f old-1 -f old-2",
+            "-d -f old-1 -f old-2",
+            "--list-archives -v --null-output",
+            "--print-stats --no-humanize-numbers",
+        ]
+    );
+    let report = json_report(&output);
+    assert_eq!(report["snapshots"][0]["storage"]["compressed_size"], 400);
+    assert_eq!(report["snapshots"][0]["storage"]["unique_compressed_size"], 40);
+    assert!(report["snapshots"][3].get("storage").is_none());
+    assert_eq!(report["storage"]["before"]["unique_compressed_size"], 2160);
+    assert_eq!(report["storage"]["expected"]["unique_compressed_size"], 80);
+    assert_eq!(report["storage"]["after"]["unique_compressed_size"], 2080);
+    assert_eq!(report["storage"]["freed"]["unique_compressed_size"], 80);
+    assert_eq!(report["storage"]["freed"]["total_size"], 2000);
+
+    let mock = daily_archives();
+
+    let output = mock.run(&["--print-stats", "--dry-run", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Archive  Compressed  Unique compressed\n\
+         old-1         400 B               40 B\n\
+         old-2         400 B               40 B\n\
+         \n\
+         Expected to free at least 80 B of the 2.2 kB stored\n"
+    );
+}
+
+#[test]
+fn strict_names() {
+    let mock = MockTarsnap::new(&[("old\rdump", 240), ("latest", 1)]);
+
+    let output = mock.run(&["--strict-names", "1D"]);
+
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(mock.archives().len(), 2);
+
+    let output = mock.run(&["1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["latest"]);
+}
+
+#[test]
+fn already_running() {
+    let mock = daily_archives();
+    let lock = Lock::acquire(mock.lock_path(), None).unwrap();
+
+    let output = mock.run(&["--no-wait", "1D"]);
+
+    assert_eq!(output.status.code(), Some(8));
+    assert!(stderr(&output).contains("already holding the lock"), "{}", stderr(&output));
+    assert_eq!(mock.archives().len(), 4);
+    assert!(mock.log().is_empty());
+
+    let output = mock.run(&["--lock-wait", "1s", "1D"]);
+
+    assert_eq!(output.status.code(), Some(8));
+
+    drop(lock);
+    let output = mock.run(&["--no-wait", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn default_lock_file() {
+    let mock = daily_archives();
+    let runtime_dir = mock.dir.path().join("runtime");
+    fs::create_dir(&runtime_dir).unwrap();
+    let mock = mock.env("XDG_RUNTIME_DIR", runtime_dir.to_str().unwrap());
+    let lock = Lock::acquire(runtime_dir.join("tarsnap-lifespan.lock"), None).unwrap();
+
+    let output = mock.run_with_default_lock(&["--no-wait", "1D"]);
+
+    assert_eq!(output.status.code(), Some(8));
+
+    drop(lock);
+    let output = mock.run_with_default_lock(&["--no-wait", "1D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["daily", "latest"]);
+}
+
+#[test]
+fn holds_added_while_waiting_for_lock() {
+    let mock = daily_archives();
+    let holds = mock.dir.path().join("holds.toml");
+    let holds = holds.to_str().unwrap();
+    let updated = mock.dir.path().join("updated.toml");
+    let updated = updated.to_str().unwrap();
+    for archive in &["old-2", "old-1"] {
+        let output = mock.run(&["--holds", updated, "hold", "add", archive]);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    let output = mock.run(&["--holds", holds, "hold", "add", "old-2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // hold add would wait for the lock, so the holds file is edited
+    // directly while the run is waiting
+    let lock = Lock::acquire(mock.lock_path(), None).unwrap();
+    let output = thread::scope(|scope| {
+        let waiting = scope.spawn(|| mock.run(&["--holds", holds, "1D"]));
+        thread::sleep(time::Duration::from_millis(500));
+        fs::copy(updated, holds).unwrap();
+        drop(lock);
+        waiting.join().unwrap()
+    });
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(mock.archives(), vec!["old-1", "old-2", "daily", "latest"]);
+}
+
+#[test]
+fn hold_changes_take_lock() {
+    let mock = daily_archives();
+    let path = mock.dir.path().join("holds.toml");
+    let holds = path.to_str().unwrap();
+    let lock = Lock::acquire(mock.lock_path(), None).unwrap();
+
+    let output = mock.run(&["--holds", holds, "--no-wait", "hold", "add", "old-1"]);
+
+    assert_eq!(output.status.code(), Some(8));
+    assert!(!path.exists());
+
+    drop(lock);
+    let output = mock.run(&["--holds", holds, "--no-wait", "hold", "add", "old-1"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn audit_log() {
+    let mock = daily_archives();
+    let path = mock.dir.path().join("audit.log");
+
+    let output = mock.run(&["--audit-log", path.to_str().unwrap(), "1D"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = mock.run(&["--audit-log", path.to_str().unwrap(), "--dry-run", "1D"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let lines: Vec<serde_json::Value> = fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .map(|x| serde_json::from_str(x).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0]["event"], "decision");
+    assert_eq!(lines[0]["policy"], "all: 1D; strategy: nearest; intervals: calendar");
+    assert_eq!(lines[0]["archives"].as_array().unwrap().len(), 4);
+    assert_eq!(lines[0]["keep"], serde_json::json!(["daily", "latest"]));
+    assert_eq!(lines[0]["delete"], serde_json::json!(["old-1", "old-2"]));
+    assert_eq!(lines[1]["event"], "result");
+    assert_eq!(lines[1]["run"], lines[0]["run"]);
+    assert_eq!(lines[1]["archives"].as_array().unwrap().len(), 2);
+    assert_eq!(lines[1]["archives"][0]["status"], "deleted");
+    assert_eq!(lines[1]["result"]["status"], "deleted");
+    assert_eq!(lines[2]["dry_run"], true);
+    assert_eq!(lines[3]["result"]["status"], "nothing-to-delete");
+}
+
+#[test]
+fn unwritable_audit_log() {
+    let mock = daily_archives();
+    let path = mock.dir.path().join("missing/audit.log");
+
+    let output = mock.run(&["--audit-log", path.to_str().unwrap(), "1D"]);
+
+    assert_eq!(output.status.code(), Some(9));
+    assert!(stderr(&output).contains("audit.log"), "{}", stderr(&output));
+    assert_eq!(mock.archives().len(), 4);
+}
+
+#[test]
+fn metrics_file() {
+    let mock = daily_archives();
+    let path = mock.dir.path().join("tarsnap.prom");
+
+    let output = mock.run(&["--metrics-file", path.to_str().unwrap(), "2D"]);
+
+    assert!(output.status.success(), "{}", stderr(&output));
+    let metrics = fs::read_to_string(&path).unwrap();
+    assert!(metrics.contains("\ntarsnap_lifespan_archives_before 4\n"), "{}", metrics);
+    assert!(metrics.contains("\ntarsnap_lifespan_archives_after 2\n"));
+    assert!(metrics.contains("\ntarsnap_lifespan_archives_deleted 2\n"));
+    assert!(metrics.contains(
+        "\ntarsnap_lifespan_generation_slots_requested{group=\"all\",generation=\"2D\"} 2\n"
+    ));
+    assert!(metrics.contains(
+        "\ntarsnap_lifespan_generation_slots_filled{group=\"all\",generation=\"2D\"} 1\n"
+    ));
+    assert!(metrics.contains("\ntarsnap_lifespan_success 1\n"));
+    assert!(metrics.ends_with("# EOF\n"));
+
+    let mock = mock.env("MOCK_TARSNAP_FAIL_LIST", "1");
+    let output = mock.run(&["--metrics-file", path.to_str().unwrap(), "2D"]);
+
+    assert_eq!(output.status.code(), Some(5));
+    let metrics = fs::read_to_string(&path).unwrap();
+    assert!(metrics.contains("\ntarsnap_lifespan_success 0\n"));
+    assert!(!metrics.contains("archives_before"));
+}
+
+#[test]
+fn metrics_file_on_early_failure() {
+    let mock = daily_archives();
+    let path = mock.dir.path().join("tarsnap.prom");
+    let lock = Lock::acquire(mock.lock_path(), None).unwrap();
+
+    let output = mock.run(&["--metrics-file", path.to_str().unwrap(), "--no-wait", "1D"]);
+
+    assert_eq!(output.status.code(), Some(8));
+    let metrics = fs::read_to_string(&path).unwrap();
+    assert!(metrics.contains("\ntarsnap_lifespan_success 0\n"), "{}", metrics);
+    assert!(metrics.contains("\ntarsnap_lifespan_last_run_timestamp_seconds "));
+    drop(lock);
+    fs::remove_file(&path).unwrap();
+
+    let config = mock.dir.path().join("config.toml");
+    let missing = mock.dir.path().join("missing");
+    fs::write(&config, format!(
+        "[policy.default]\nmetrics-file = {:?}\nprotect-file = {:?}\n",
+        path, missing
+    )).unwrap();
+    let output = mock.run(&["--config", config.to_str().unwrap(), "1D"]);
+
+    assert_eq!(output.status.code(), Some(2));
+    let metrics = fs::read_to_string(&path).unwrap();
+    assert!(metrics.contains("\ntarsnap_lifespan_success 0\n"), "{}", metrics);
+    assert!(mock.log().is_empty());
+}